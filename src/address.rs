@@ -0,0 +1,256 @@
+//! RFC 5322 address lists as found in From, To, Cc etc, parsed into the shape IMAP uses for ENVELOPE
+//!
+use crate::fetch::{imap_string, imap_nstring};
+
+/// One entry in an address list. Groups are flattened the way IMAP sends them, a [Address::GroupStart]
+/// followed by the group members and a [Address::GroupEnd]
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum Address{
+    Mailbox{ display: Option<String>, route: Option<String>, local: String, domain: Option<String> },
+    GroupStart(String),
+    GroupEnd,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token{
+    Word(String),
+    Quoted(String),
+    Comment(String),
+    Special(char),
+}
+
+/// Splits a header value into words, quoted strings, comments and the specials that matter in addresses
+///
+fn tokenise(value: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chars = value.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            ' ' | '\t' | '\r' | '\n' => { chars.next(); }
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => s.extend(chars.next()),
+                        '"' => break,
+                        '\r' | '\n' => {}
+                        _ => s.push(c),
+                    }
+                }
+                tokens.push(Token::Quoted(s));
+            }
+            '(' => {
+                chars.next();
+                let mut depth = 1;
+                let mut s = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => s.extend(chars.next()),
+                        '(' => { depth += 1; s.push(c) }
+                        ')' => {
+                            depth -= 1;
+                            if depth == 0 { break }
+                            s.push(c);
+                        }
+                        _ => s.push(c),
+                    }
+                }
+                tokens.push(Token::Comment(s.trim().to_owned()));
+            }
+            '[' => {
+                // Domain literals such as [192.168.0.1] are kept whole
+                let mut s = String::new();
+                for c in chars.by_ref() {
+                    s.push(c);
+                    if c == ']' { break }
+                }
+                tokens.push(Token::Word(s));
+            }
+            '<' | '>' | '@' | ',' | ':' | ';' => {
+                chars.next();
+                tokens.push(Token::Special(c));
+            }
+            _ => {
+                let mut s = String::new();
+                while let Some(&c) = chars.peek() {
+                    if " \t\r\n\"()<>@,:;[".contains(c) { break }
+                    s.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(s));
+            }
+        }
+    }
+    tokens
+}
+
+/// Parses an address list. Anything unparseable is skipped rather than failing the whole header, and the old
+/// `user@host (Display Name)` and bare `Display Name user@host` forms are understood
+///
+pub fn parse_address_list(value: &str) -> Vec<Address> {
+    let tokens = tokenise(value);
+    let mut pos = 0;
+    let mut addresses = Vec::new();
+    while pos < tokens.len() {
+        parse_address(&tokens, &mut pos, &mut addresses, false);
+    }
+    addresses
+}
+
+/// Parses one mailbox or group starting at `pos`, stopping after the `,` (or `;` inside a group) that ends it
+///
+fn parse_address(tokens: &[Token], pos: &mut usize, out: &mut Vec<Address>, in_group: bool) {
+    let mut words: Vec<String> = Vec::new();
+    let mut comment: Option<String> = None;
+    let first = out.len();
+
+    while let Some(token) = tokens.get(*pos) {
+        *pos += 1;
+        match token {
+            Token::Word(w) | Token::Quoted(w) => words.push(w.to_owned()),
+            Token::Comment(c) => if !c.is_empty() { comment = Some(c.to_owned()) },
+            Token::Special(':') if !in_group => {
+                out.push(Address::GroupStart(words.join(" ")));
+                while let Some(token) = tokens.get(*pos) {
+                    if *token == Token::Special(';') {
+                        *pos += 1;
+                        break
+                    }
+                    parse_address(tokens, pos, out, true);
+                }
+                out.push(Address::GroupEnd);
+                // Skip to the comma after the group
+                while let Some(token) = tokens.get(*pos) {
+                    *pos += 1;
+                    if *token == Token::Special(',') { break }
+                }
+                return
+            }
+            Token::Special('<') => {
+                let (route, local, domain) = parse_angle_addr(tokens, pos);
+                let display = match words.is_empty() {
+                    true => None,
+                    false => Some(words.join(" ")),
+                };
+                words.clear();
+                if let Some(local) = local {
+                    out.push(Address::Mailbox{ display, route, local, domain });
+                }
+            }
+            Token::Special('@') => {
+                // Bare addr-spec, a run of words before it means the legacy `Display Name user@host` form
+                let local = words.pop().unwrap_or_default();
+                let domain = parse_domain(tokens, pos);
+                let display = match words.is_empty() {
+                    true => None,
+                    false => Some(words.join(" ")),
+                };
+                words.clear();
+                out.push(Address::Mailbox{ display, route: None, local, domain: Some(domain) });
+            }
+            Token::Special(',') => break,
+            Token::Special(';') if in_group => {
+                *pos -= 1;
+                break
+            }
+            Token::Special(_) => {}
+        }
+    }
+
+    // A trailing comment is the display name in the `user@host (Display Name)` form
+    if let (Some(comment), true) = (comment, out.len() > first) {
+        if let Some(Address::Mailbox{ display: display @ None, .. }) = out.last_mut() {
+            *display = Some(comment);
+        }
+    }
+    // Route-less words with no domain, e.g. `undisclosed-recipients` or a local user
+    if !words.is_empty() {
+        out.push(Address::Mailbox{ display: None, route: None, local: words.join(" "), domain: None });
+    }
+}
+
+/// Parses `[route:]local@domain>` after a `<`
+///
+fn parse_angle_addr(tokens: &[Token], pos: &mut usize) -> (Option<String>, Option<String>, Option<String>) {
+    let mut route = None;
+    let mut local = String::new();
+    let mut domain = None;
+    // An obsolete source route such as <@a.example,@b.example:user@c.example>
+    if tokens.get(*pos) == Some(&Token::Special('@')) {
+        let mut route_text = String::new();
+        while let Some(token) = tokens.get(*pos) {
+            *pos += 1;
+            match token {
+                Token::Special(':') => break,
+                Token::Special(c) => route_text.push(*c),
+                Token::Word(w) | Token::Quoted(w) => route_text.push_str(w),
+                Token::Comment(_) => {}
+            }
+        }
+        route = Some(route_text);
+    }
+    while let Some(token) = tokens.get(*pos) {
+        *pos += 1;
+        match token {
+            Token::Word(w) | Token::Quoted(w) => local.push_str(w),
+            Token::Special('@') => domain = Some(parse_domain(tokens, pos)),
+            Token::Special('>') => break,
+            _ => {}
+        }
+    }
+    if local.is_empty() {
+        return (route, None, None)
+    }
+    (route, Some(local), domain)
+}
+
+/// Reads the words making up a domain, stopping before any special
+///
+fn parse_domain(tokens: &[Token], pos: &mut usize) -> String {
+    let mut domain = String::new();
+    while let Some(token) = tokens.get(*pos) {
+        match token {
+            Token::Word(w) => domain.push_str(w),
+            _ => break,
+        }
+        *pos += 1;
+    }
+    domain
+}
+
+/// Formats an address list for ENVELOPE, `NIL` when the list is empty
+///
+pub fn format_address_list(addresses: &[Address]) -> String {
+    if addresses.is_empty() { return "NIL".to_owned() }
+    let items: Vec<String> = addresses.iter().map(|address| match address {
+        Address::Mailbox{ display, route, local, domain } => format!("({} {} {} {})",
+            imap_nstring(display.as_deref()),
+            imap_nstring(route.as_deref()),
+            imap_string(local),
+            imap_nstring(domain.as_deref()),
+        ),
+        Address::GroupStart(name) => format!("(NIL NIL {} NIL)", imap_string(name)),
+        Address::GroupEnd => "(NIL NIL NIL NIL)".to_owned(),
+    }).collect();
+    format!("({})", items.join(""))
+}
+
+#[test]
+fn address_list_forms(){
+    let list = parse_address_list("\"Test, Adam\" <adam@example.scot>, bob@foo.com (Bob Bar), <@relay.example:carol@bar.com>");
+    assert_eq!(list, vec![
+        Address::Mailbox{ display: Some("Test, Adam".into()), route: None, local: "adam".into(), domain: Some("example.scot".into()) },
+        Address::Mailbox{ display: Some("Bob Bar".into()), route: None, local: "bob".into(), domain: Some("foo.com".into()) },
+        Address::Mailbox{ display: None, route: Some("@relay.example".into()), local: "carol".into(), domain: Some("bar.com".into()) },
+    ]);
+}
+#[test]
+fn address_list_groups(){
+    let list = parse_address_list("Team: a@x.com, b@y.com;, undisclosed-recipients:;");
+    assert_eq!(format_address_list(&list),
+        "((NIL NIL \"Team\" NIL)(NIL NIL \"a\" \"x.com\")(NIL NIL \"b\" \"y.com\")(NIL NIL NIL NIL)\
+        (NIL NIL \"undisclosed-recipients\" NIL)(NIL NIL NIL NIL))");
+    assert_eq!(format_address_list(&parse_address_list("")), "NIL");
+}
@@ -0,0 +1,112 @@
+//! Change log shared by every session. Storage records what happens to each mailbox here, keyed by its folder, so a
+//! session can learn about mail delivered or flags changed by others without rereading the whole folder
+//!
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Changes kept per mailbox, a session that falls further behind than this compares its view with the index instead
+///
+const MAX_CHANGES: usize = 1000;
+
+/// Logs for every mailbox touched since the server started
+///
+static LOGS: Mutex<Vec<Log>> = Mutex::new(Vec::new());
+
+/// Bumped on every change to any mailbox, lets an idle session check cheaply whether there is anything to look at
+///
+static VERSION: AtomicU64 = AtomicU64::new(0);
+
+/// Bumped whenever any user's subscriptions change, for NOTIFY SubscriptionChange
+///
+static SUBSCRIPTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Something that happened to a message, by UID since sequence numbers differ between sessions
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change{
+    Added(u32),
+    Expunged(u32),
+    Flags(u32),
+}
+
+/// The recent changes of one mailbox, `first` is the position of the oldest one kept
+///
+#[derive(Debug)]
+struct Log{
+    mailbox: PathBuf,
+    first: u64,
+    changes: VecDeque<Change>,
+}
+
+/// Adds changes to the log of a mailbox
+///
+pub fn record(mailbox: &Path, changes: impl IntoIterator<Item = Change>) {
+    let mut logs = LOGS.lock().unwrap();
+    let index = match logs.iter().position(|l| l.mailbox == mailbox) {
+        Some(index) => index,
+        None => {
+            logs.push(Log{ mailbox: mailbox.to_path_buf(), first: 0, changes: VecDeque::new() });
+            logs.len() - 1
+        }
+    };
+    let log = &mut logs[index];
+    let before = log.changes.len();
+    log.changes.extend(changes);
+    if log.changes.len() != before { VERSION.fetch_add(1, Ordering::SeqCst); }
+    while log.changes.len() > MAX_CHANGES {
+        log.changes.pop_front();
+        log.first += 1;
+    }
+}
+
+/// A number that changes whenever any mailbox does
+///
+pub fn version() -> u64 {
+    VERSION.load(Ordering::SeqCst)
+}
+
+/// Notes that a user's subscriptions changed, waking idle sessions as a mailbox change would
+///
+pub fn record_subscriptions() {
+    SUBSCRIPTIONS.fetch_add(1, Ordering::SeqCst);
+    VERSION.fetch_add(1, Ordering::SeqCst);
+}
+
+/// A number that changes whenever anyone's subscriptions do
+///
+pub fn subscriptions_version() -> u64 {
+    SUBSCRIPTIONS.load(Ordering::SeqCst)
+}
+
+/// The position the next change to a mailbox will have, a session keeps it to know what it has already seen
+///
+pub fn position(mailbox: &Path) -> u64 {
+    let logs = LOGS.lock().unwrap();
+    logs.iter().find(|l| l.mailbox == mailbox).map_or(0, |l| l.first + l.changes.len() as u64)
+}
+
+/// The changes made since `from` and the new position, [None] when the oldest of them have already been dropped
+///
+pub fn since(mailbox: &Path, from: u64) -> Option<(Vec<Change>, u64)> {
+    let logs = LOGS.lock().unwrap();
+    let Some(log) = logs.iter().find(|l| l.mailbox == mailbox) else { return Some((Vec::new(), 0)) };
+    if from < log.first { return None }
+    let skip = (from - log.first) as usize;
+    Some((log.changes.iter().skip(skip).copied().collect(), log.first + log.changes.len() as u64))
+}
+
+#[test]
+fn change_log_positions(){
+    let mailbox = Path::new("change-log-test");
+    let start = position(mailbox);
+    let before = version();
+    record(mailbox, [Change::Added(1), Change::Flags(1)]);
+    assert_eq!(since(mailbox, start), Some((vec![Change::Added(1), Change::Flags(1)], start + 2)));
+    assert_eq!(since(mailbox, start + 1).unwrap().0, vec![Change::Flags(1)]);
+    assert_ne!(version(), before);
+
+    record(mailbox, (0..MAX_CHANGES as u32).map(Change::Expunged));
+    assert_eq!(since(mailbox, start), None);
+}
@@ -0,0 +1,43 @@
+//! Settings read from config.aml, loaded once when the server starts and handed to each connection
+//!
+use crate::email;
+use std::time::Duration;
+
+/// Seconds a client may IDLE when config.aml has no `idle_timeout`, RFC 2177 clients restart IDLE within 29 minutes
+static DEFAULT_IDLE_TIMEOUT: u64 = 30 * 60;
+
+/// The settings a connection uses while it runs
+///
+#[derive(Debug, Clone)]
+pub struct Config{
+    /// Whether logging in waits for STARTTLS, on unless `login_requires_tls = false`
+    pub login_requires_tls: bool,
+    /// How much of a message a session may hold in memory at once, `session_memory_limit`
+    pub session_memory_limit: usize,
+    /// How long a client may IDLE before it is sent BYE, `idle_timeout` in seconds
+    pub idle_timeout: Duration,
+}
+
+impl Default for Config{
+    fn default() -> Self {
+        Self{
+            login_requires_tls: true,
+            session_memory_limit: email::MAX_BUFFERED,
+            idle_timeout: Duration::from_secs(DEFAULT_IDLE_TIMEOUT),
+        }
+    }
+}
+
+impl Config{
+    /// Reads the settings from a config file, anything missing or unreadable keeps its default
+    ///
+    pub fn load(path: &str) -> Self {
+        let config = aml::load(path);
+        let defaults = Self::default();
+        Self{
+            login_requires_tls: !config.get("login_requires_tls").is_some_and(|v| v.eq_ignore_ascii_case("false")),
+            session_memory_limit: config.get("session_memory_limit").and_then(|l| l.parse().ok()).unwrap_or(defaults.session_memory_limit),
+            idle_timeout: config.get("idle_timeout").and_then(|t| t.parse().ok()).map_or(defaults.idle_timeout, Duration::from_secs),
+        }
+    }
+}
@@ -1,201 +1,528 @@
 /// This struct is responsible for opening a email file from the file system then picking out the data that IMAP requires
 /// 
-use regex::Regex;
 use std::path::{PathBuf, Path};
 use std::io::Read;
-use chrono::{DateTime, Utc};
+use std::cell::RefCell;
+use chrono::{DateTime, FixedOffset, Utc};
 use std::fs;
+use std::borrow::Cow;
+use std::ops::Range;
+use std::io::{Seek, SeekFrom};
 use crate::error::{Result,Error};
+use crate::encoding;
+use crate::storage;
+use crate::parser::{self, Arg};
+use crate::mime::{Part, Source};
+use crate::header::HeaderMap;
+use crate::address::{self, Address};
+use crate::fetch::{FetchAttr, imap_nstring};
+use crate::stream::Chunk;
 
-static MAIL_ROOT: &'static str = "D:/MAILSERVER";
+/// Messages up to this size are kept in memory once parsed, larger ones are read back from disk by range when served
+///
+pub const MAX_BUFFERED: usize = 1024 * 1024;
+
+/// A stored message parsed where it lies, read a range at a time when it is too large to hold in memory
+///
+struct FileSource{
+    file: RefCell<fs::File>,
+    size: usize,
+}
+
+impl Source for FileSource{
+    fn size(&self) -> usize {
+        self.size
+    }
+    fn read(&self, range: Range<usize>) -> Cow<'_, [u8]> {
+        let mut file = self.file.borrow_mut();
+        let mut data = Vec::with_capacity(range.len());
+        // A file cut short while it is read gives fewer bytes, which the parser treats as the end
+        let _ = file.seek(SeekFrom::Start(range.start as u64))
+            .and_then(|_| (&mut *file).take(range.len() as u64).read_to_end(&mut data));
+        Cow::Owned(data)
+    }
+}
+
+/// Where the bytes of a section come from, a range of the stored message or data built for the response
+///
+enum Section{
+    Range(Range<usize>),
+    Built(Vec<u8>),
+}
 /// Struct containing the email contents
 ///
 #[derive(Debug)] 
 pub struct Email{
     uid: String,
     seq: String,
-    email_contents: String,
+    /// [None] when the message is larger than [MAX_BUFFERED] and ranges are read from `path` instead
+    email_contents: Option<Vec<u8>>,
+    /// Most of the message that may be read into memory at once
+    max_buffered: usize,
+    size: usize,
     path: PathBuf,
+    flags: Vec<String>,
+    internal_date: Option<DateTime<FixedOffset>>,
+    modseq: u64,
+    /// Whether the client enabled UTF8=ACCEPT, otherwise header text in ENVELOPE is kept to 7-bit
+    utf8: bool,
+    mime: Part,
 }
 
 impl Email{
-    /// Creates a new struct and gets the contents of an email and stores as a [String]
+    /// Creates a new struct and gets the contents of an email, kept as bytes so any message on disk can be served
     /// 
     pub fn new(uid: &str, seq: &str, email_path: impl AsRef<Path>) -> Result<Self> {
-        let mut buf = vec![];
-        let mut f = fs::File::open(&email_path).map_err(Error::IO)?;
-        f.read_to_end(&mut buf).map_err(Error::IO)?;
-        let email = String::from_utf8(buf).map_err(Error::UTF8)?;
+        Self::load(uid, seq, email_path, MAX_BUFFERED)
+    }
+    /// Reads and parses the message, keeping the bytes only when there are at most `max_buffered` of them. Larger
+    /// messages are parsed from the file a range at a time and never read whole, mailboxes rewrite files with bare LF
+    /// line endings as CRLF when they index them so the offsets hold. Smaller ones with bare LF are normalised here
+    ///
+    pub fn load(uid: &str, seq: &str, email_path: impl AsRef<Path>, max_buffered: usize) -> Result<Self> {
+        let mut file = fs::File::open(&email_path).map_err(Error::IO)?;
+        let size = file.metadata().map_err(Error::IO)?.len() as usize;
+        let (mime, size, email) = match size > max_buffered {
+            true => (Part::parse(&FileSource{ file: RefCell::new(file), size }), size, None),
+            false => {
+                let mut buf = Vec::with_capacity(size);
+                file.read_to_end(&mut buf).map_err(Error::IO)?;
+                let email = match storage::normalise_crlf(&buf) {
+                    Cow::Owned(normalised) => normalised,
+                    Cow::Borrowed(_) => buf,
+                };
+                (Part::parse(&email[..]), email.len(), Some(email))
+            }
+        };
 
         Ok(Self{
             uid: uid.to_owned(),
             seq: seq.to_owned(),
             email_contents: email,
+            max_buffered,
+            size,
             path: PathBuf::from(email_path.as_ref()),
+            flags: Vec::new(),
+            internal_date: None,
+            modseq: 0,
+            utf8: false,
+            mime,
         })
     }
-    /// Parses the to field of an email
+    /// Sets the flags reported by FETCH, they live in the mailbox index rather than the message
+    ///
+    pub fn with_flags(mut self, flags: Vec<String>) -> Self {
+        self.flags = flags;
+        self
+    }
+    /// Sets the INTERNALDATE, which is recorded in the mailbox index when the message is delivered or appended
+    ///
+    pub fn with_internal_date(mut self, date: DateTime<FixedOffset>) -> Self {
+        self.internal_date = Some(date);
+        self
+    }
+    /// Sets the MODSEQ, kept in the mailbox index alongside the flags it tracks
+    ///
+    pub fn with_modseq(mut self, modseq: u64) -> Self {
+        self.modseq = modseq;
+        self
+    }
+    /// Sets whether UTF-8 may be sent as it is in ENVELOPE (RFC 6855), otherwise it goes out as encoded-words
+    ///
+    pub fn with_utf8(mut self, utf8: bool) -> Self {
+        self.utf8 = utf8;
+        self
+    }
+}
+
+/// Single header fields for callers outside the server, FETCH builds ENVELOPE from [Email::headers] instead
+///
+#[allow(dead_code, clippy::wrong_self_convention)]
+impl Email{
+    /// Parses the to field of an email, returning the user, domain and display name (`NIL` if none) of the first recipient
     /// 
     pub fn to_header(&self) -> Result<(String, String, String)> {
-
-        let to = Regex::new(r"(?mi)^TO: (.*)\r").unwrap();
-
-        let find_to = to.captures(&self.email_contents)
-            .ok_or(Error::ToFieldMissing)?;
-        let line = find_to.get(1).ok_or(Error::ToFieldMissing)?
-            .as_str().replace(&['<','>'][..], "");
-
-        let mut split = line.rsplitn(2, " "); // Remove To: and split email and displayname 
-
-        let mut tmp = split.next().unwrap().split("@"); // Splits email address into user and domain
-        let to_user = tmp.next().unwrap().to_owned();
-        let to_domain = tmp.next().unwrap().to_owned();
-        let to_display_name = split.next().unwrap_or("NIL").to_owned(); // Gets displayname if exists
-
-        Ok((to_user, to_domain, to_display_name))
+        self.first_address("To").ok_or(Error::ToFieldMissing)
     }
-    /// Parses the from field of an email
+    /// Parses the from field of an email, returning the user, domain and display name (`NIL` if none)
     /// 
     pub fn from_header(&self) -> Result<(String, String, String)> {
-
-        let to = Regex::new(r"(?mi)^FROM:.*").unwrap();
-
-        let find_to = to.find(&self.email_contents);
-        let line = find_to.map_or("", |m| m.as_str()).replace(&['<','>','\r'][..], "");
-        let mut split = line.splitn(2, " ").last().unwrap().rsplitn(2, " "); // Remove To: and split email and displayname 
-
-        let mut tmp = split.next().unwrap().split("@"); // Splits email address into user and domain
-        let to_user = tmp.next().unwrap_or("").to_owned();
-        let to_domain = tmp.next().unwrap_or("").to_owned();
-        let to_display_name = split.next().unwrap_or("NIL").to_owned(); // Gets displayname if exists
-
-        Ok((to_user, to_domain, to_display_name))
+        Ok(self.first_address("From").unwrap_or_default())
     }
-    /// Parses the from field of an email and returns it as rfc2822 format
+    /// The first mailbox in an address header as (user, domain, display name) with `NIL` standing in for no display name
+    ///
+    fn first_address(&self, name: &str) -> Option<(String, String, String)> {
+        self.headers().addresses(name).into_iter().find_map(|address| match address {
+            Address::Mailbox{ display, local, domain, .. } => {
+                Some((local, domain.unwrap_or_default(), display.unwrap_or("NIL".to_owned())))
+            }
+            _ => None,
+        })
+    }
+    /// Returns the date field of an email in rfc2822 format, empty if there is none
     /// 
     pub fn date_header(&self) -> Result<String> {
-    
-        let to = Regex::new("(?mi)^DATE:.*").unwrap();
-        let find_to = to.find(&self.email_contents);
-    
-        let line = find_to.map_or("", |m| m.as_str()).replace(&['\r'][..], "");
-        let date_string = line.splitn(2, " ").last().unwrap().to_owned(); 
-    
-        Ok(date_string)
-    }
-    /// Parses the subject field of an email and returns it
+        Ok(self.headers().get("Date").unwrap_or_default().to_owned())
+    }
+    /// Returns the subject field of an email with any RFC 2047 encoded-words decoded, empty if there is none
     /// 
     pub fn subject_header(&self) -> Result<String> {
-    
-        let to = Regex::new(r"(?mi)^SUBJECT:.*").unwrap();
-        let find_to = to.find(&self.email_contents);
-    
-        let line = find_to.map_or("", |m| m.as_str()).replace(&['\r'][..], "");
-        let subject = line.splitn(2, " ").last().unwrap().to_owned(); 
-    
-        Ok(subject)
-    }
-    /// Takes the creation date of the file as the creation date and converts to an IMAP friendly format [String]
-    /// 
-    fn internal_date(&self) -> Result<String> {
-        let metadata = fs::metadata(&self.path).map_err(Error::IO)?;
-        let created_date = metadata.created().map_err(Error::IO)?;
-        let dt: DateTime<Utc> = created_date.into();
-        Ok(dt.format("%Y-%b-%d %H:%M:%S %z").to_string())
+        Ok(self.headers().get("Subject").map(encoding::decode_encoded_words).unwrap_or_default())
     }
-    /// Format a response with just the UID
-    /// 
-    pub fn format_response(&self, args: &str) -> String{
-        //println!("Args: {}", args);
+}
 
-        match args{
-            "(UID)" => format!("{} FETCH (UID {})\r\n", self.seq, self.uid),
+impl Email{
+    /// The top level header fields
+    ///
+    pub fn headers(&self) -> &HeaderMap {
+        &self.mime.headers
+    }
+    /// Reads a byte range of the message, from memory when it is held or from the file otherwise
+    ///
+    fn read(&self, range: Range<usize>) -> Result<Cow<'_, [u8]>> {
+        if let Some(data) = &self.email_contents { return Ok(Cow::Borrowed(&data[range])) }
 
-            "(UID FLAGS RFC822.SIZE BODY.PEEK[] INTERNALDATE)" => {
-                self.fetch_uid_flags_RFC822_bodypeek().unwrap()
-            },
+        let mut file = fs::File::open(&self.path).map_err(Error::IO)?;
+        file.seek(SeekFrom::Start(range.start as u64)).map_err(Error::IO)?;
+        let mut data = vec![0u8; range.len()];
+        file.read_exact(&mut data).map_err(Error::IO)?;
+        Ok(Cow::Owned(data))
+    }
+    /// [Email::read] for data that has to be held whole, ranges of a message on disk over the buffering limit give
+    /// [Error::TooLarge]
+    ///
+    fn read_bounded(&self, range: Range<usize>) -> Result<Cow<'_, [u8]>> {
+        if self.email_contents.is_none() && range.len() > self.max_buffered { return Err(Error::TooLarge) }
+        self.read(range)
+    }
+    /// The text parts of the body one after another, each with its own transfer encoding and charset undone.
+    /// Attachments and other parts that are not text are left out, as are text parts too large to buffer
+    ///
+    pub fn decoded_body(&self) -> String {
+        let texts: Vec<String> = self.mime.text_parts().iter().filter_map(|part| {
+            let body = self.read_bounded(part.body.clone()).ok()?;
+            let charset = part.content_type.param("charset").unwrap_or_default();
+            Some(encoding::decode_body(&body, &part.encoding, &charset))
+        }).collect();
+        texts.join("\r\n")
+    }
+    /// Size of the message in bytes, line endings are CRLF by now so this matches what FETCH sends
+    ///
+    pub fn size(&self) -> usize {
+        self.size
+    }
+    /// Returns a body section from its section spec as used by `BODY[section]` FETCH and CATENATE URLs. `""` is the
+    /// whole message, part numbers walk the MIME tree and HEADER/TEXT after a part number refer to the message it encapsulates
+    ///
+    pub fn section(&self, spec: &str) -> Result<Cow<'_, [u8]>> {
+        match self.locate(spec)? {
+            Section::Range(range) => self.read(range),
+            Section::Built(data) => Ok(Cow::Owned(data)),
+        }
+    }
+    /// Finds where a section spec points, HEADER.FIELDS is built from the parsed fields and everything else is a range
+    ///
+    fn locate(&self, spec: &str) -> Result<Section> {
+        let (numbers, rest) = split_section(spec)?;
+        let part = self.mime.find(&numbers).ok_or(Error::SectionNotFound)?;
+        let message = match numbers.is_empty() {
+            true => Some(part),
+            false => part.message.as_deref(),
+        };
+        let upper = rest.to_uppercase();
+        let range = match upper.as_str() {
+            "" if numbers.is_empty() => 0..self.size,
+            "" => part.body.clone(),
+            "MIME" if !numbers.is_empty() => part.header.clone(),
+            "HEADER" => message.ok_or(Error::SectionNotFound)?.header.clone(),
+            "TEXT" => message.ok_or(Error::SectionNotFound)?.body.clone(),
+            _ if upper.starts_with("HEADER.FIELDS") => {
+                let headers = &message.ok_or(Error::SectionNotFound)?.headers;
+                let (not, list) = match upper.starts_with("HEADER.FIELDS.NOT") {
+                    true => (true, &rest["HEADER.FIELDS.NOT".len()..]),
+                    false => (false, &rest["HEADER.FIELDS".len()..]),
+                };
+                let names = match parser::parse_args(list)?.as_slice() {
+                    [Arg::List(names)] => names.iter().filter_map(|n| n.as_str().map(|n| n.to_owned())).collect::<Vec<_>>(),
+                    _ => return Err(Error::BadArguments("Invalid HEADER.FIELDS list")),
+                };
+                let names: Vec<&str> = names.iter().map(|n| n.as_str()).collect();
+                return Ok(Section::Built(headers.filter(&names, not)))
+            }
+            _ => return Err(Error::SectionNotFound),
+        };
+        Ok(Section::Range(range))
+    }
+    /// The section as a response [Chunk], ranges of a message not held in memory are left for the stream to copy
+    /// from the file. `partial` is the `<origin.length>` of the request
+    ///
+    fn section_chunk(&self, spec: &str, partial: Option<(usize, usize)>) -> Result<Chunk> {
+        let clip = |range: Range<usize>| match partial {
+            Some((origin, length)) => {
+                let start = (range.start + origin).min(range.end);
+                start..(start + length).min(range.end)
+            }
+            None => range,
+        };
+        match self.locate(spec)? {
+            Section::Range(range) if self.email_contents.is_none() => Ok(Chunk::File{ path: self.path.clone(), range: clip(range) }),
+            Section::Range(range) => Ok(Chunk::Bytes(self.read(clip(range))?.into_owned())),
+            Section::Built(data) => Ok(Chunk::Bytes(data[clip(0..data.len())].to_vec())),
+        }
+    }
+    /// Returns a part with its content transfer encoding undone for `BINARY[section]`. Only part numbers are allowed,
+    /// `""` is the whole message as stored. Encodings other than base64 and quoted-printable give [Error::UnknownCte]
+    /// and parts too large to decode in memory [Error::TooLarge]
+    ///
+    pub fn binary(&self, spec: &str) -> Result<Cow<'_, [u8]>> {
+        let (numbers, rest) = split_section(spec)?;
+        if !rest.is_empty() { return Err(Error::BadArguments("BINARY takes part numbers only")) }
+        if numbers.is_empty() { return self.read_bounded(0..self.size) }
 
-            "(UID FLAGS)" => { self.fetch_uid_flags().unwrap() },
-            _ => {todo!()}
+        let part = self.mime.find(&numbers).ok_or(Error::SectionNotFound)?;
+        let body = self.read_bounded(part.body.clone())?;
+        match part.encoding.to_ascii_lowercase().as_str() {
+            "7bit" | "8bit" | "binary" => Ok(body),
+            "base64" => encoding::decode_base64(&body).map(Cow::Owned).ok_or(Error::UnknownCte),
+            "quoted-printable" => Ok(Cow::Owned(encoding::decode_quoted_printable(&body, false))),
+            _ => Err(Error::UnknownCte),
         }
     }
+    /// The internal date in the RFC 3501 `date-time` format, messages opened outside a mailbox fall back to the time
+    /// the file was written
+    ///
+    fn internal_date(&self) -> Result<String> {
+        let date = match self.internal_date {
+            Some(date) => date,
+            None => DateTime::<Utc>::from(fs::metadata(&self.path).and_then(|m| m.modified()).map_err(Error::IO)?).into(),
+        };
+        Ok(storage::format_date_time(&date))
+    }
+    /// Builds the FETCH response for the message from the parsed attribute list, items are answered in the order asked.
+    /// Literals of a message held on disk come back as file ranges so the stream can copy them without buffering
+    ///
+    pub fn format_response(&self, attrs: &[FetchAttr]) -> Result<Vec<Chunk>>{
+        let mut chunks = vec![Chunk::Bytes(format!("{} FETCH (", self.seq).into_bytes())];
+        for (index, attr) in attrs.iter().enumerate() {
+            if index > 0 { push_bytes(&mut chunks, b" ") }
+            let text = match attr {
+                FetchAttr::Uid => format!("UID {}", self.uid),
+                FetchAttr::Flags => format!("FLAGS ({})", self.flags.join(" ")),
+                FetchAttr::InternalDate => format!("INTERNALDATE \"{}\"", self.internal_date()?),
+                FetchAttr::Rfc822Size => format!("RFC822.SIZE {}", self.size()),
+                FetchAttr::ModSeq => format!("MODSEQ ({})", self.modseq),
+                FetchAttr::Envelope => format!("ENVELOPE {}", self.envelope()),
+                FetchAttr::Body => format!("BODY {}", self.mime.structure(false, self.utf8)),
+                FetchAttr::BodyStructure => format!("BODYSTRUCTURE {}", self.mime.structure(true, self.utf8)),
+                FetchAttr::BodySection{ section, partial, .. } => {
+                    let name = match partial {
+                        Some((origin, _)) => format!("BODY[{}]<{}> ", section, origin),
+                        None => format!("BODY[{}] ", section),
+                    };
+                    match self.section_chunk(section, *partial) {
+                        Ok(chunk) => push_literal(&mut chunks, &name, chunk),
+                        Err(_) => push_bytes(&mut chunks, format!("BODY[{}] NIL", section).as_bytes()),
+                    }
+                    continue
+                }
+                FetchAttr::Binary{ section, partial, .. } => {
+                    // A part that is missing is NIL like a BODY section, one that cannot be decoded fails the FETCH
+                    let data = match self.binary(section) {
+                        Err(e @ (Error::UnknownCte | Error::TooLarge)) => return Err(e),
+                        Err(_) => {
+                            push_bytes(&mut chunks, format!("BINARY[{}] NIL", section).as_bytes());
+                            continue
+                        }
+                        Ok(data) => data,
+                    };
+                    let (name, data) = match partial {
+                        Some((origin, length)) => {
+                            let start = (*origin).min(data.len());
+                            let end = (start + length).min(data.len());
+                            (format!("BINARY[{}]<{}> ~", section, origin), &data[start..end])
+                        }
+                        None => (format!("BINARY[{}] ~", section), &data[..]),
+                    };
+                    push_bytes(&mut chunks, format!("{}{{{}}}\r\n", name, data.len()).as_bytes());
+                    push_bytes(&mut chunks, data);
+                    continue
+                }
+                FetchAttr::BinarySize{ section } => match self.binary(section) {
+                    Err(e @ (Error::UnknownCte | Error::TooLarge)) => return Err(e),
+                    data => format!("BINARY.SIZE[{}] {}", section, data.map_or(0, |d| d.len())),
+                },
+                FetchAttr::Rfc822 => {
+                    push_literal(&mut chunks, "RFC822 ", self.section_chunk("", None)?);
+                    continue
+                }
+                FetchAttr::Rfc822Header => {
+                    push_literal(&mut chunks, "RFC822.HEADER ", self.section_chunk("HEADER", None)?);
+                    continue
+                }
+                FetchAttr::Rfc822Text => {
+                    push_literal(&mut chunks, "RFC822.TEXT ", self.section_chunk("TEXT", None)?);
+                    continue
+                }
+            };
+            push_bytes(&mut chunks, text.as_bytes());
+        }
+        push_bytes(&mut chunks, b")\r\n");
+        Ok(chunks)
+    }
+    /// Builds the ENVELOPE structure from the top level headers
+    ///
+    fn envelope(&self) -> String {
+        envelope(self.headers(), self.utf8)
+    }
+}
 
-    pub fn fetch_uid_flags(&self) -> Result<String>{
-        let data = ""; // This is what the bytes refer to, the new line adds +2 though
-        let seq_num = &self.seq;
-        let uid = &self.uid;
-
-        let bytes = data.len()+2;
-        Ok(format!(
-            "{seq_num} FETCH (UID {uid} FLAGS (\\Seen))\r\n",
-            seq_num = seq_num,
-            uid = uid,
-        ))
-
-    }
-
-    // pub fn fetch_info(&self) -> Result<String>{
-    //     let (to_user, to_domain, to_display_name) = self.to_header()?;
-    //     let (from_user, from_domain, from_display_name) = self.from_header()?;
-    //     let date = self.date_header()?;
-    //     let subject = self.subject_header()?;
-    //     let internal_date = self.internal_date()?;
-
-    //     // TODO FIELDS
-    //     //let message_id = "<CADkb2rHmFeg1D01=a=n9xFZ5LxqH5FsWHT_dPxMyK7O4v1EKUA@mail.gmail.com>";
-    //     let message_id = "NIL";
-    //     let data = ""; // This is what the bytes refer to, the new line adds +2 though
-    //     let seq_num = &self.seq;
-    //     let uid = &self.uid;
-
-    //     let bytes = data.len()+2;
-    //     Ok(format!(
-    //         "{seq_num} FETCH (UID {uid} FLAGS (\\RECENT) ENVELOPE (\"{date}\" \"{subject}\" \
-    //         ((\"{from_display_name}\" NIL \"{from_user}\" \"{from_domain}\")) NIL NIL ((\"{to_display_name}\" NIL \
-    //         \"{to_user}\" \"{to_domain}\")) NIL NIL NIL {message_id}) INTERNALDATE \"{internal_date}\" \
-    //         BODY[HEADER.FIELDS (References)] {{{bytes}}}\r\n{data}\r\n)\r\n",
-    //         seq_num = seq_num,
-    //         uid = uid,
-    //         date = date,
-    //         subject = subject,
-    //         from_display_name = from_display_name,
-    //         from_user = from_user,
-    //         from_domain = from_domain,
-    //         to_display_name = to_display_name,
-    //         to_user = to_user,
-    //         to_domain = to_domain,
-    //         message_id = message_id,
-    //         internal_date = internal_date,
-    //         bytes = bytes,
-    //         data = data,
-    //     ))
-    // }
-
-    // (UID FLAGS RFC822.SIZE BODY.PEEK[] INTERNALDATE)
-    pub fn fetch_uid_flags_RFC822_bodypeek(&self) -> Result<String>{
-        let internal_date = self.internal_date()?;
-        let data = &self.email_contents; // This is what the bytes refer to, the new line adds +2 though
-        let seq_num = &self.seq;
-        let uid = &self.uid;
-
-        let bytes = data.len()+2;
-        Ok(format!(
-            "{seq_num} FETCH (UID {uid} FLAGS (\\RECENT) RFC822.SIZE 53000 BODY[] {{{bytes}}}\r\n\
-            {data}\r\n INTERNALDATE \"{internal_date}\")\r\n",
-            seq_num = seq_num,
-            uid = uid,
-            internal_date = internal_date,
-            bytes = bytes,
-            data = data,
-        ))
+/// Builds an ENVELOPE from header fields, also used for message/rfc822 parts. Sender and reply-to fall back to
+/// from when absent as RFC 3501 asks. Unless `utf8` is set, UTF-8 in the subject and display names of a message
+/// with internationalised headers (RFC 6532) is sent as encoded-words
+///
+pub fn envelope(headers: &HeaderMap, utf8: bool) -> String {
+    let text = |value: &str| match utf8 {
+        true => value.to_owned(),
+        false => encoding::encode_words(value).into_owned(),
+    };
+    let field = |name: &str| imap_nstring(headers.get(name).map(&text).as_deref());
+    let addresses = |name: &str| headers.addresses(name).into_iter().map(|address| match address {
+        Address::Mailbox{ display, route, local, domain } => Address::Mailbox{ display: display.as_deref().map(&text), route, local, domain },
+        Address::GroupStart(name) => Address::GroupStart(text(&name)),
+        Address::GroupEnd => Address::GroupEnd,
+    }).collect::<Vec<_>>();
+    let from = addresses("From");
+    let or_from = |name: &str| {
+        let list = addresses(name);
+        address::format_address_list(if list.is_empty() { &from } else { &list })
+    };
+    format!("({} {} {} {} {} {} {} {} {} {})",
+        field("Date"),
+        field("Subject"),
+        address::format_address_list(&from),
+        or_from("Sender"),
+        or_from("Reply-To"),
+        address::format_address_list(&addresses("To")),
+        address::format_address_list(&addresses("Cc")),
+        address::format_address_list(&addresses("Bcc")),
+        field("In-Reply-To"),
+        field("Message-ID"),
+    )
+}
+
+/// Splits a section spec into its part numbers and what follows them, `1.2.HEADER` gives `[1, 2]` and `HEADER`
+///
+fn split_section(spec: &str) -> Result<(Vec<usize>, &str)> {
+    let mut numbers = Vec::new();
+    let mut rest = spec.trim();
+    loop {
+        let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        if digits == 0 { break }
+        let number = rest[..digits].parse().map_err(|_| Error::SectionNotFound)?;
+        if number == 0 { return Err(Error::SectionNotFound) }
+        numbers.push(number);
+        rest = &rest[digits..];
+        match rest.strip_prefix('.') {
+            Some(after) => rest = after,
+            None => break,
+        }
+    }
+    Ok((numbers, rest))
+}
+
+/// Appends bytes to a response, joining them onto the last chunk when that is also in memory
+///
+fn push_bytes(chunks: &mut Vec<Chunk>, data: &[u8]) {
+    match chunks.last_mut() {
+        Some(Chunk::Bytes(last)) => last.extend_from_slice(data),
+        _ => chunks.push(Chunk::Bytes(data.to_vec())),
+    }
+}
+
+/// Appends a FETCH item whose value is an IMAP literal, the data is copied as is so its length is exact. A name
+/// ending in `~` makes it a literal8 for BINARY
+///
+fn push_literal(chunks: &mut Vec<Chunk>, name: &str, data: Chunk) {
+    let length = match &data {
+        Chunk::Bytes(bytes) => bytes.len(),
+        Chunk::File{ range, .. } => range.len(),
+    };
+    push_bytes(chunks, format!("{}{{{}}}\r\n", name, length).as_bytes());
+    match data {
+        Chunk::Bytes(bytes) => push_bytes(chunks, &bytes),
+        file => chunks.push(file),
     }
 }
 
 #[test]
-fn test_internal_date(){
+fn test_section_header_text(){
     let email = Email::new("1", "1", "test_emails/NoDisplayNames.eml").unwrap();
-    let date = email.internal_date().unwrap();
-    assert_eq!(date, "2021-Nov-23 11:26:52 +0000");
+    assert!(email.section("HEADER").unwrap().ends_with(b"Subject: Testing Email\r\n\r\n"));
+    assert!(email.section("TEXT").unwrap().starts_with(b"This is a body"));
+    assert!(email.section("2").is_err());
+}
+#[test]
+fn test_eight_bit_message(){
+    let email = Email::new("1", "1", "test_emails/Latin1.eml").unwrap();
+    assert_eq!(&*email.section("TEXT").unwrap(), b"Gr\xfc\xdfe aus K\xf6ln\r\n");
+    assert_eq!(email.decoded_body(), "Grüße aus Köln\r\n");
+}
+#[test]
+fn test_binary_decoding(){
+    let email = Email::new("1", "1", "test_emails/Multipart.eml").unwrap();
+    assert_eq!(&*email.binary("1.1").unwrap(), b"Plain body");
+    assert_eq!(email.decoded_body(), "Plain body\r\n<p>HTML body</p>\r\nForwarded body");
+    assert!(email.binary("1.HEADER").is_err());
+    let (attrs, _) = crate::fetch::parse_attributes("(BINARY.PEEK[2.1]<0.4> BINARY.SIZE[1.2])", false).unwrap();
+    assert_eq!(email.format_response(&attrs).unwrap(),
+        vec![Chunk::Bytes(b"1 FETCH (BINARY[2.1]<0> ~{4}\r\nForw BINARY.SIZE[1.2] 16)\r\n".to_vec())]);
+    let (attrs, _) = crate::fetch::parse_attributes("(BINARY[9] BINARY.SIZE[9] UID)", false).unwrap();
+    assert_eq!(email.format_response(&attrs).unwrap(),
+        vec![Chunk::Bytes(b"1 FETCH (BINARY[9] NIL BINARY.SIZE[9] 0 UID 1)\r\n".to_vec())]);
+}
+#[test]
+fn test_section_mime_parts(){
+    let email = Email::new("1", "1", "test_emails/Multipart.eml").unwrap();
+    assert_eq!(&*email.section("1.2").unwrap(), b"<p>HTML body</p>");
+    assert_eq!(&*email.section("1.1.MIME").unwrap(), b"Content-Type: text/plain; charset=utf-8\r\n\r\n");
+    assert_eq!(&*email.section("2.HEADER.FIELDS (Subject)").unwrap(), b"Subject: Forwarded\r\n\r\n");
+    assert_eq!(&*email.section("2.1").unwrap(), b"Forwarded body");
+    assert_eq!(&*email.section("HEADER.FIELDS (received TO)").unwrap(),
+        b"To: adam.test@example.scot\r\nReceived: from a\r\n  by b\r\n\r\n");
+    assert!(email.section("HEADER.FIELDS.NOT (From To Subject Received MIME-Version)").unwrap().starts_with(b"Content-Type:"));
+    assert!(email.section("3").is_err());
+}
+#[test]
+fn test_format_response(){
+    let email = Email::new("7", "1", "test_emails/NoDisplayNames.eml").unwrap().with_flags(vec!["\\Seen".into()]);
+    let (attrs, _) = crate::fetch::parse_attributes("(FLAGS BODY.PEEK[TEXT]<0.9> RFC822.SIZE)", true).unwrap();
+    assert_eq!(email.format_response(&attrs).unwrap(), vec![Chunk::Bytes(format!(
+        "1 FETCH (UID 7 FLAGS (\\Seen) BODY[TEXT]<0> {{9}}\r\nThis is a RFC822.SIZE {})\r\n", email.size()).into_bytes())]);
+}
+#[test]
+fn test_format_response_from_file(){
+    let email = Email::load("3", "2", "test_emails/Multipart.eml", 0).unwrap();
+    let (attrs, _) = crate::fetch::parse_attributes("(BODY.PEEK[2.1]<4.4> BODY[2.HEADER.FIELDS (Subject)])", false).unwrap();
+    let chunks = email.format_response(&attrs).unwrap();
+    let Chunk::File{ range, .. } = &chunks[1] else { panic!("expected a file range") };
+    assert_eq!(range.len(), 4);
+    assert_eq!(&*email.section("2.1").unwrap(), b"Forwarded body");
+    assert_eq!(chunks[0], Chunk::Bytes(b"2 FETCH (BODY[2.1]<4> {4}\r\n".to_vec()));
+    assert_eq!(chunks[2], Chunk::Bytes(b" BODY[2.HEADER.FIELDS (Subject)] {22}\r\nSubject: Forwarded\r\n\r\n)\r\n".to_vec()));
+}
+#[test]
+fn test_envelope(){
+    let email = Email::new("1", "1", "test_emails/DisplayNames.eml").unwrap();
+    assert_eq!(email.envelope(),
+        "(\"Tue, 23 Nov 2021 16:56:32 +0000\" \"Testing Email\" ((\"Adam the Rusty\" NIL \"adam.bar\" \"foo.com\")) \
+        ((\"Adam the Rusty\" NIL \"adam.bar\" \"foo.com\")) ((\"Adam the Rusty\" NIL \"adam.bar\" \"foo.com\")) \
+        ((\"Adam Test\" NIL \"adam.test\" \"example.scot\")) NIL NIL NIL NIL)");
+}
+#[test]
+fn test_internal_date(){
+    let date = storage::parse_date_time("23-Nov-2021 11:26:52 +0000").unwrap();
+    let email = Email::new("1", "1", "test_emails/NoDisplayNames.eml").unwrap().with_internal_date(date);
+    assert_eq!(email.internal_date().unwrap(), "23-Nov-2021 11:26:52 +0000");
 }
 
 #[test]
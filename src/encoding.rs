@@ -0,0 +1,320 @@
+//! Decoding of encoded message text, content transfer encodings (base64, quoted-printable), charsets and
+//! RFC 2047 encoded-words in headers, plus the case folding used for text comparisons and the encodings that keep
+//! responses 7-bit for clients that have not enabled UTF-8
+//!
+use encoding_rs::Encoding;
+use std::borrow::Cow;
+
+static BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Decodes base64, whitespace and line breaks are skipped. Returns [None] on characters outside the alphabet
+///
+pub fn decode_base64(input: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(input.len() / 4 * 3);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for &c in input {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            b' ' | b'\t' | b'\r' | b'\n' => continue,
+            _ => return None,
+        };
+        acc = (acc << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Decodes quoted-printable. In `header` mode (the RFC 2047 Q encoding) an underscore is a space
+///
+pub fn decode_quoted_printable(input: &[u8], header: bool) -> Vec<u8> {
+    let hex = |c: u8| (c as char).to_digit(16);
+    let mut out = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            b'=' if input[i + 1..].starts_with(b"\r\n") => i += 3,
+            b'=' if input[i + 1..].starts_with(b"\n") => i += 2,
+            b'=' if i + 2 < input.len() => match (hex(input[i + 1]), hex(input[i + 2])) {
+                (Some(high), Some(low)) => {
+                    out.push((high * 16 + low) as u8);
+                    i += 3;
+                }
+                _ => {
+                    out.push(b'=');
+                    i += 1;
+                }
+            },
+            b'_' if header => {
+                out.push(b' ');
+                i += 1;
+            }
+            c => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+/// Converts text in the named charset to a [String], unknown charsets give [None]
+///
+pub fn decode_charset(bytes: &[u8], charset: &str) -> Option<String> {
+    // RFC 2231 allows a language after the charset, `utf-8*en`
+    let label = charset.split('*').next().unwrap_or("").trim();
+    let encoding = match label.to_ascii_lowercase().as_str() {
+        "" | "us-ascii" | "ascii" => encoding_rs::UTF_8,
+        _ => Encoding::for_label(label.as_bytes())?,
+    };
+    let (text, _, _) = encoding.decode(bytes);
+    Some(text.into_owned())
+}
+
+/// Decodes content transfer encoding then charset, used for single part bodies
+///
+pub fn decode_body(body: &[u8], transfer_encoding: &str, charset: &str) -> String {
+    let decoded = match transfer_encoding.trim().to_ascii_lowercase().as_str() {
+        "base64" => decode_base64(body).unwrap_or_else(|| body.to_vec()),
+        "quoted-printable" => decode_quoted_printable(body, false),
+        _ => body.to_vec(),
+    };
+    decode_charset(&decoded, charset).unwrap_or_else(|| String::from_utf8_lossy(&decoded).to_string())
+}
+
+/// Decodes RFC 2047 encoded-words such as `=?UTF-8?B?7ZWc6riA?=` in a header value.
+/// Whitespace between two encoded-words is dropped, anything that does not decode is left as it was
+///
+pub fn decode_encoded_words(value: &str) -> String {
+    let mut out = String::new();
+    let mut rest = value;
+    let mut last_was_word = false;
+    while let Some(start) = rest.find("=?") {
+        let (before, candidate) = rest.split_at(start);
+        match decode_word(candidate) {
+            Some((decoded, used)) => {
+                if !(last_was_word && before.trim().is_empty()) {
+                    out.push_str(before);
+                }
+                out.push_str(&decoded);
+                rest = &candidate[used..];
+                last_was_word = true;
+            }
+            None => {
+                out.push_str(before);
+                out.push_str("=?");
+                rest = &candidate[2..];
+                last_was_word = false;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Decodes one `=?charset?encoding?text?=` word at the start of `s`, returning the text and the bytes consumed
+///
+fn decode_word(s: &str) -> Option<(String, usize)> {
+    let inner = &s[2..];
+    let (charset, rest) = inner.split_once('?')?;
+    let (encoding, rest) = rest.split_once('?')?;
+    let end = rest.find("?=")?;
+    let text = &rest[..end];
+    if text.contains(' ') { return None }
+
+    let bytes = match encoding {
+        "B" | "b" => decode_base64(text.as_bytes())?,
+        "Q" | "q" => decode_quoted_printable(text.as_bytes(), true),
+        _ => return None,
+    };
+    let used = 2 + charset.len() + 1 + encoding.len() + 1 + end + 2;
+    Some((decode_charset(&bytes, charset)?, used))
+}
+
+/// Reads a parameter from a structured header, reassembling RFC 2231 pieces such as `filename*0*=utf-8''a%20b` and
+/// `filename*1=c` and decoding their charset. Plain values are returned with any RFC 2047 encoded-words decoded, which
+/// some mailers use for filenames even though the RFC does not allow it
+///
+pub fn decode_parameter(params: &[(String, String)], name: &str) -> Option<String> {
+    if let Some((_, value)) = params.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)) {
+        return Some(decode_encoded_words(value))
+    }
+
+    let mut pieces: Vec<(usize, bool, &str)> = Vec::new();
+    for (key, value) in params {
+        let suffix = match key.get(..name.len()) {
+            Some(prefix) if prefix.eq_ignore_ascii_case(name) => &key[name.len()..],
+            _ => continue,
+        };
+        let Some(suffix) = suffix.strip_prefix('*') else { continue };
+        let (index, encoded) = match suffix.strip_suffix('*') {
+            _ if suffix.is_empty() => (0, true),
+            Some(index) => (index.parse().ok()?, true),
+            None => (suffix.parse().ok()?, false),
+        };
+        pieces.push((index, encoded, value));
+    }
+    if pieces.is_empty() { return None }
+    pieces.sort_by_key(|(index, _, _)| *index);
+
+    // Only the first piece names the charset and language, `charset'language'value`
+    let mut charset = "";
+    let mut bytes = Vec::new();
+    for (i, (_, encoded, value)) in pieces.iter().enumerate() {
+        let mut value = *value;
+        if i == 0 && *encoded {
+            let mut fields = value.splitn(3, '\'');
+            if let (Some(set), Some(_language), Some(rest)) = (fields.next(), fields.next(), fields.next()) {
+                charset = set;
+                value = rest;
+            }
+        }
+        match encoded {
+            true => bytes.extend(percent_decode(value)),
+            false => bytes.extend_from_slice(value.as_bytes()),
+        }
+    }
+    Some(decode_charset(&bytes, charset).unwrap_or_else(|| String::from_utf8_lossy(&bytes).to_string()))
+}
+
+/// Undoes `%XX` escapes, a `%` not followed by two hex digits is kept
+///
+pub fn percent_decode(value: &str) -> Vec<u8> {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match value.get(i + 1..i + 3).filter(|_| bytes[i] == b'%').and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(byte) => {
+                out.push(byte);
+                i += 3;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+/// Encodes base64 without line breaks, `padded` adds the trailing `=` that modified UTF-7 leaves out
+///
+fn encode_base64(input: &[u8], padded: bool) -> String {
+    let mut out = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |acc, (i, &b)| acc | (b as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            out.push(BASE64[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+        if padded { out.push_str(&"=="[..3 - chunk.len()]) }
+    }
+    out
+}
+
+/// Turns a header value holding UTF-8 into an RFC 2047 encoded-word, ASCII values are left as they are
+///
+pub fn encode_words(value: &str) -> Cow<'_, str> {
+    match value.is_ascii() {
+        true => Cow::Borrowed(value),
+        false => Cow::Owned(format!("=?UTF-8?B?{}?=", encode_base64(value.as_bytes(), true))),
+    }
+}
+
+/// Encodes a mailbox name in the modified UTF-7 of RFC 3501 5.1.3, `Entwürfe` becomes `Entw&APw-rfe`
+///
+pub fn encode_mutf7(name: &str) -> Cow<'_, str> {
+    if name.chars().all(|c| (' '..='~').contains(&c) && c != '&') { return Cow::Borrowed(name) }
+    let mut out = String::new();
+    let mut wide: Vec<u16> = Vec::new();
+    let flush = |wide: &mut Vec<u16>, out: &mut String| {
+        if wide.is_empty() { return }
+        let bytes: Vec<u8> = wide.drain(..).flat_map(|u| u.to_be_bytes()).collect();
+        out.push('&');
+        out.push_str(&encode_base64(&bytes, false).replace('/', ","));
+        out.push('-');
+    };
+    for c in name.chars() {
+        match c {
+            '&' => {
+                flush(&mut wide, &mut out);
+                out.push_str("&-");
+            }
+            ' '..='~' => {
+                flush(&mut wide, &mut out);
+                out.push(c);
+            }
+            _ => wide.extend(c.encode_utf16(&mut [0; 2]).iter()),
+        }
+    }
+    flush(&mut wide, &mut out);
+    Cow::Owned(out)
+}
+
+/// Decodes a modified UTF-7 mailbox name, [None] when it is not valid modified UTF-7
+///
+pub fn decode_mutf7(name: &str) -> Option<String> {
+    let mut out = String::new();
+    let mut rest = name;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        let end = start + rest[start..].find('-')?;
+        match &rest[start + 1..end] {
+            "" => out.push('&'),
+            encoded => {
+                let bytes = decode_base64(encoded.replace(',', "/").as_bytes())?;
+                let wide: Vec<u16> = bytes.chunks_exact(2).map(|b| u16::from_be_bytes([b[0], b[1]])).collect();
+                out.push_str(&String::from_utf16(&wide).ok()?);
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Some(out)
+}
+
+/// Full Unicode case folding so `STRASSE` finds `Straße` and Hangul or other uncased text compares exactly
+///
+pub fn fold_case(s: &str) -> String {
+    caseless::default_case_fold_str(s)
+}
+
+#[test]
+fn decode_encoded_word_header(){
+    assert_eq!(decode_encoded_words("=?UTF-8?B?7ZWc6riA?= test"), "한글 test");
+    assert_eq!(decode_encoded_words("=?ISO-8859-1?Q?Gr=FC=DFe?= =?UTF-8?Q?_aus_Berlin?="), "Grüße aus Berlin");
+    assert_eq!(decode_encoded_words("plain =?bogus text"), "plain =?bogus text");
+}
+#[test]
+fn decode_rfc2231_parameters(){
+    let params = |list: &[(&str, &str)]| list.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<Vec<_>>();
+    assert_eq!(decode_parameter(&params(&[("filename*", "UTF-8''%ED%95%9C%EA%B8%80.txt")]), "FILENAME").unwrap(), "한글.txt");
+    let split = params(&[("name*1", " report.pdf"), ("name*0*", "iso-8859-1'de'Gr%FC%DFe")]);
+    assert_eq!(decode_parameter(&split, "name").unwrap(), "Grüße report.pdf");
+    assert_eq!(decode_parameter(&params(&[("name", "=?UTF-8?B?7ZWc6riA?=")]), "name").unwrap(), "한글");
+    assert_eq!(decode_parameter(&split, "filename"), None);
+}
+#[test]
+fn decode_transfer_encodings(){
+    assert_eq!(decode_body(b"7ZWc6riA\r\n", "base64", "utf-8"), "한글");
+    assert_eq!(decode_body(b"Gr=FC=DFe=\r\n!", "quoted-printable", "iso-8859-1"), "Grüße!");
+    assert_eq!(fold_case("STRASSE"), fold_case("Straße"));
+}
+#[test]
+fn encode_for_seven_bit_clients(){
+    assert_eq!(encode_words("한글"), "=?UTF-8?B?7ZWc6riA?=");
+    assert_eq!(decode_encoded_words(&encode_words("Grüße")), "Grüße");
+    assert_eq!(encode_mutf7("Entwürfe & 台北"), "Entw&APw-rfe &- &U,BTFw-");
+    assert_eq!(decode_mutf7("Entw&APw-rfe &- &U,BTFw-").unwrap(), "Entwürfe & 台北");
+    assert_eq!(decode_mutf7("Inbox").unwrap(), "Inbox");
+    assert_eq!(decode_mutf7("bad&AP"), None);
+}
@@ -1,16 +1,26 @@
 pub type Result<T> = std::result::Result<T, self::Error>;
 
+// Details carried by variants are only read through Debug, when an error is reported to the client
+#[allow(dead_code)]
 #[derive(Debug)]
 pub enum Error{
     IO(std::io::Error),
-    UTF8(std::string::FromUtf8Error),
-    CommandNotRecognised,
     FolderLookup(&'static str),
-    InvalidToField,
     CantReadEmail,
-    TimeDate(chrono::ParseError),
-    IMAPCopyErr,
     TCPReadTimeout,
-    NotAMonth,
+    ConnectionClosed,
     ToFieldMissing,
+    BadArguments(&'static str),
+    BadUrl(String),
+    MailboxNotFound,
+    NoMailboxSelected,
+    ReadOnly,
+    SectionNotFound,
+    InvalidDate,
+    BadCharset,
+    UnknownCte,
+    BadEvent,
+    TlsUnavailable,
+    LiteralTooBig,
+    TooLarge,
 }
\ No newline at end of file
@@ -0,0 +1,209 @@
+//! FETCH data items, the attribute list a client sends is parsed into [FetchAttr]s which [crate::email::Email]
+//! turns into a response one item at a time
+//!
+use crate::error::{Result, Error};
+use crate::parser::{self, Arg};
+
+/// A single FETCH data item
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum FetchAttr{
+    Uid,
+    Flags,
+    InternalDate,
+    Rfc822Size,
+    Envelope,
+    Body,
+    BodyStructure,
+    /// `BODY[section]<partial>` and `BODY.PEEK[...]`, partial is the origin octet and the length
+    BodySection{ peek: bool, section: String, partial: Option<(usize, usize)> },
+    /// `BINARY[part]<partial>` and `BINARY.PEEK[...]` (RFC 3516), the part with its transfer encoding undone
+    Binary{ peek: bool, section: String, partial: Option<(usize, usize)> },
+    BinarySize{ section: String },
+    Rfc822,
+    Rfc822Header,
+    Rfc822Text,
+    /// The message's mod-sequence (RFC 7162)
+    ModSeq,
+}
+
+/// What may follow the attributes of a FETCH, `(CHANGEDSINCE modseq)` limits it to messages changed since then and
+/// `VANISHED` asks UID FETCH for the UIDs expunged since then too (RFC 7162)
+///
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Modifiers{
+    pub changed_since: Option<u64>,
+    pub vanished: bool,
+}
+
+/// Parses the attribute part of a FETCH command, either a macro, a single item or a parenthesised list, followed by
+/// any modifiers. `uid` adds the UID item which UID FETCH responses must always contain and CHANGEDSINCE adds MODSEQ
+///
+pub fn parse_attributes(args: &str, uid: bool) -> Result<(Vec<FetchAttr>, Modifiers)> {
+    let mut parsed = parser::parse_args(args)?;
+    let modifiers = match parsed.len() {
+        2 => match parsed.pop() {
+            Some(Arg::List(list)) => parse_modifiers(&list)?,
+            _ => return Err(Error::BadArguments("Invalid FETCH modifiers")),
+        },
+        _ => Modifiers::default(),
+    };
+    let items = match parsed.as_slice() {
+        [Arg::List(list)] => list.clone(),
+        [Arg::Atom(atom)] => match atom.to_uppercase().as_str() {
+            "ALL" => atoms(&["FLAGS", "INTERNALDATE", "RFC822.SIZE", "ENVELOPE"]),
+            "FAST" => atoms(&["FLAGS", "INTERNALDATE", "RFC822.SIZE"]),
+            "FULL" => atoms(&["FLAGS", "INTERNALDATE", "RFC822.SIZE", "ENVELOPE", "BODY"]),
+            _ => parsed.clone(),
+        },
+        _ => return Err(Error::BadArguments("Invalid FETCH attributes")),
+    };
+
+    let mut attrs = Vec::new();
+    for item in items {
+        match item {
+            Arg::Atom(atom) => attrs.push(parse_attribute(&atom)?),
+            _ => return Err(Error::BadArguments("Invalid FETCH attribute")),
+        }
+    }
+    if uid && !attrs.contains(&FetchAttr::Uid) {
+        attrs.insert(0, FetchAttr::Uid);
+    }
+    if modifiers.changed_since.is_some() && !attrs.contains(&FetchAttr::ModSeq) {
+        attrs.push(FetchAttr::ModSeq);
+    }
+    Ok((attrs, modifiers))
+}
+
+/// Parses the modifier list that follows the attributes
+///
+fn parse_modifiers(list: &[Arg]) -> Result<Modifiers> {
+    let mut modifiers = Modifiers::default();
+    let mut list = list.iter();
+    while let Some(modifier) = list.next() {
+        match modifier.as_str().map(|m| m.to_uppercase()).as_deref() {
+            Some("CHANGEDSINCE") => {
+                let modseq = list.next().and_then(Arg::as_str).and_then(|m| m.parse().ok());
+                modifiers.changed_since = Some(modseq.ok_or(Error::BadArguments("CHANGEDSINCE needs a mod-sequence"))?);
+            }
+            Some("VANISHED") => modifiers.vanished = true,
+            _ => return Err(Error::BadArguments("Unknown FETCH modifier")),
+        }
+    }
+    if modifiers.vanished && modifiers.changed_since.is_none() {
+        return Err(Error::BadArguments("VANISHED needs CHANGEDSINCE"))
+    }
+    Ok(modifiers)
+}
+
+/// Expands a macro into the atoms it stands for
+///
+fn atoms(names: &[&str]) -> Vec<Arg> {
+    names.iter().map(|n| Arg::Atom(n.to_string())).collect()
+}
+
+/// Parses one data item, `BODY[...]` items keep the section as the client wrote it so it can be echoed back
+///
+fn parse_attribute(atom: &str) -> Result<FetchAttr> {
+    let (name, section) = match atom.find('[') {
+        Some(open) => {
+            let close = atom.rfind(']').ok_or(Error::BadArguments("Unterminated section"))?;
+            (&atom[..open], Some((&atom[open + 1..close], &atom[close + 1..])))
+        }
+        None => (atom, None),
+    };
+    let name = name.to_uppercase();
+
+    if let Some((section, partial)) = section {
+        let section = section.to_owned();
+        let partial = parse_partial(partial)?;
+        return Ok(match name.as_str() {
+            "BODY" => FetchAttr::BodySection{ peek: false, section, partial },
+            "BODY.PEEK" => FetchAttr::BodySection{ peek: true, section, partial },
+            "BINARY" => FetchAttr::Binary{ peek: false, section, partial },
+            "BINARY.PEEK" => FetchAttr::Binary{ peek: true, section, partial },
+            "BINARY.SIZE" if partial.is_none() => FetchAttr::BinarySize{ section },
+            _ => return Err(Error::BadArguments("Unknown FETCH attribute")),
+        })
+    }
+
+    Ok(match name.as_str() {
+        "UID" => FetchAttr::Uid,
+        "FLAGS" => FetchAttr::Flags,
+        "INTERNALDATE" => FetchAttr::InternalDate,
+        "RFC822.SIZE" => FetchAttr::Rfc822Size,
+        "ENVELOPE" => FetchAttr::Envelope,
+        "BODY" => FetchAttr::Body,
+        "BODYSTRUCTURE" => FetchAttr::BodyStructure,
+        "RFC822" => FetchAttr::Rfc822,
+        "RFC822.HEADER" => FetchAttr::Rfc822Header,
+        "RFC822.TEXT" => FetchAttr::Rfc822Text,
+        "MODSEQ" => FetchAttr::ModSeq,
+        _ => return Err(Error::BadArguments("Unknown FETCH attribute")),
+    })
+}
+
+/// Parses the `<origin.length>` suffix of a section
+///
+fn parse_partial(partial: &str) -> Result<Option<(usize, usize)>> {
+    if partial.is_empty() { return Ok(None) }
+    let bad = || Error::BadArguments("Invalid partial");
+    let inner = partial.strip_prefix('<').and_then(|p| p.strip_suffix('>')).ok_or_else(bad)?;
+    let (origin, length) = inner.split_once('.').ok_or_else(bad)?;
+    Ok(Some((origin.parse().map_err(|_| bad())?, length.parse().map_err(|_| bad())?)))
+}
+
+/// Whether fetching these items sets `\Seen`, body and binary sections without PEEK, RFC822 and RFC822.TEXT do
+///
+pub fn sets_seen(attrs: &[FetchAttr]) -> bool {
+    attrs.iter().any(|attr| matches!(attr, FetchAttr::BodySection{ peek: false, .. } | FetchAttr::Binary{ peek: false, .. } |
+        FetchAttr::Rfc822 | FetchAttr::Rfc822Text))
+}
+
+/// Formats text as an IMAP string, quoted when possible and as a literal when it holds line breaks or quotes would not do
+///
+pub fn imap_string(s: &str) -> String {
+    if s.contains(['\r', '\n']) || s.len() > 1024 {
+        format!("{{{}}}\r\n{}", s.len(), s)
+    } else {
+        format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// Formats an optional value as an IMAP nstring, `NIL` when absent
+///
+pub fn imap_nstring(s: Option<&str>) -> String {
+    s.map_or("NIL".to_owned(), imap_string)
+}
+
+#[test]
+fn fetch_parse_macros_and_sections(){
+    assert_eq!(parse_attributes("FAST", false).unwrap().0, vec![FetchAttr::Flags, FetchAttr::InternalDate, FetchAttr::Rfc822Size]);
+    let (attrs, _) = parse_attributes("(FLAGS BODY.PEEK[HEADER.FIELDS (From Subject)] BODY[]<0.2048>)", true).unwrap();
+    assert_eq!(attrs, vec![
+        FetchAttr::Uid,
+        FetchAttr::Flags,
+        FetchAttr::BodySection{ peek: true, section: "HEADER.FIELDS (From Subject)".into(), partial: None },
+        FetchAttr::BodySection{ peek: false, section: "".into(), partial: Some((0, 2048)) },
+    ]);
+    assert!(parse_attributes("(UID BOGUS)", false).is_err());
+    assert_eq!(parse_attributes("(BINARY.PEEK[1.2]<0.10> BINARY.SIZE[2])", false).unwrap().0, vec![
+        FetchAttr::Binary{ peek: true, section: "1.2".into(), partial: Some((0, 10)) },
+        FetchAttr::BinarySize{ section: "2".into() },
+    ]);
+    assert!(!sets_seen(&attrs[..3]));
+    assert!(sets_seen(&attrs));
+
+    let (attrs, modifiers) = parse_attributes("FLAGS (CHANGEDSINCE 12345)", false).unwrap();
+    assert_eq!(attrs, vec![FetchAttr::Flags, FetchAttr::ModSeq]);
+    assert_eq!(modifiers.changed_since, Some(12345));
+    assert!(parse_attributes("(FLAGS) (CHANGEDSINCE)", false).is_err());
+    assert!(parse_attributes("(FLAGS) (VANISHED CHANGEDSINCE 1)", true).unwrap().1.vanished);
+    assert!(parse_attributes("(FLAGS) (VANISHED)", true).is_err());
+}
+#[test]
+fn fetch_string_quoting(){
+    assert_eq!(imap_string("say \"hi\""), "\"say \\\"hi\\\"\"");
+    assert_eq!(imap_string("a\r\nb"), "{4}\r\na\r\nb");
+    assert_eq!(imap_nstring(None), "NIL");
+}
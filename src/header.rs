@@ -0,0 +1,102 @@
+//! Header block parsing. The block ends at the first blank line, folded fields are unfolded for their values while
+//! the raw bytes of every field are kept so HEADER.FIELDS can be served exactly as stored
+//!
+use chrono::{DateTime, FixedOffset};
+use crate::address::{self, Address};
+use crate::encoding;
+
+/// One header field, `value` is unfolded and trimmed and `raw` is the field as stored including its line break
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field{
+    pub name: String,
+    pub value: String,
+    pub raw: Vec<u8>,
+}
+
+/// The header fields of a message or MIME part in the order they appear, repeated fields such as Received are all kept
+///
+#[derive(Debug, Clone, Default)]
+pub struct HeaderMap{
+    fields: Vec<Field>,
+}
+
+impl HeaderMap{
+    /// Parses a header block, stopping at the blank line. Lines that are neither a field nor a continuation are skipped
+    ///
+    pub fn parse(block: &[u8]) -> Self {
+        let mut fields: Vec<Field> = Vec::new();
+        let mut in_field = false;
+        for line in block.split_inclusive(|&b| b == b'\n') {
+            let content = line.strip_suffix(b"\n").unwrap_or(line);
+            let content = content.strip_suffix(b"\r").unwrap_or(content);
+            if content.is_empty() { break }
+
+            if content.starts_with(b" ") || content.starts_with(b"\t") {
+                if let (true, Some(field)) = (in_field, fields.last_mut()) {
+                    field.value = format!("{}{}", field.value, String::from_utf8_lossy(content)).trim().to_owned();
+                    field.raw.extend_from_slice(line);
+                }
+                continue
+            }
+            let Some(colon) = content.iter().position(|&b| b == b':') else {
+                in_field = false;
+                continue
+            };
+            fields.push(Field{
+                name: String::from_utf8_lossy(&content[..colon]).trim().to_owned(),
+                value: String::from_utf8_lossy(&content[colon + 1..]).trim().to_owned(),
+                raw: line.to_vec(),
+            });
+            in_field = true;
+        }
+        Self{ fields }
+    }
+    /// The first value of a field, names are case insensitive
+    ///
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields.iter().find(|f| f.name.eq_ignore_ascii_case(name)).map(|f| f.value.as_str())
+    }
+    /// Every value of a repeated field
+    ///
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.fields.iter().filter(move |f| f.name.eq_ignore_ascii_case(name)).map(|f| f.value.as_str())
+    }
+    /// Every value of a field with encoded-words decoded
+    ///
+    pub fn get_all_decoded(&self, name: &str) -> Vec<String> {
+        self.get_all(name).map(encoding::decode_encoded_words).collect()
+    }
+    /// The addresses in every field with the given name, repeated fields such as two To lines are combined
+    ///
+    pub fn addresses(&self, name: &str) -> Vec<Address> {
+        self.get_all(name).flat_map(address::parse_address_list).collect()
+    }
+    /// The Date field, [None] when missing or not a valid RFC 5322 date
+    ///
+    pub fn date(&self) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc2822(self.get("Date")?).ok()
+    }
+    /// Keeps the fields named in `names`, or those not named when `not` is set (HEADER.FIELDS and HEADER.FIELDS.NOT).
+    /// Fields are copied byte for byte, folding included, and a blank line ends the result
+    ///
+    pub fn filter(&self, names: &[&str], not: bool) -> Vec<u8> {
+        let mut out = Vec::new();
+        for field in &self.fields {
+            if names.iter().any(|n| n.eq_ignore_ascii_case(&field.name)) != not {
+                out.extend_from_slice(&field.raw);
+            }
+        }
+        out.extend_from_slice(b"\r\n");
+        out
+    }
+}
+
+#[test]
+fn header_map_folding_and_repeats(){
+    let headers = HeaderMap::parse(b"Received: from a\r\n\tby b\r\nTo: x@y.com\r\nReceived: from c\r\nSubject: =?UTF-8?B?7ZWc6riA?=\r\n\r\nTo: body@not.header\r\n");
+    assert_eq!(headers.get_all("received").collect::<Vec<_>>(), vec!["from a\tby b", "from c"]);
+    assert_eq!(headers.addresses("To").len(), 1);
+    assert_eq!(headers.get_all_decoded("Subject"), vec!["한글".to_owned()]);
+    assert_eq!(headers.filter(&["RECEIVED"], false), b"Received: from a\r\n\tby b\r\nReceived: from c\r\n\r\n");
+}
@@ -0,0 +1,105 @@
+//! Parses IMAP URLs (RFC 5092) as used by CATENATE to point at a section of a stored message
+//!
+use crate::error::{Result, Error};
+use crate::encoding;
+
+/// The parts of an IMAP URL that identify a message section
+///
+#[derive(Debug, PartialEq)]
+pub struct ImapUrl{
+    pub user: Option<String>,
+    pub mailbox: String,
+    pub uid_validity: Option<u32>,
+    pub uid: u32,
+    pub section: String,
+    pub partial: Option<(usize, Option<usize>)>,
+}
+
+impl TryFrom<&str> for ImapUrl{
+    type Error = Error;
+
+    /// Accepts both `imap://user@host/INBOX;UIDVALIDITY=1/;UID=2/;SECTION=1` and the server relative `/INBOX/;UID=2` forms
+    ///
+    fn try_from(url: &str) -> Result<Self> {
+        let bad = || Error::BadUrl(url.to_owned());
+
+        let mut user = None;
+        let path = if url.len() > 7 && url[..7].eq_ignore_ascii_case("imap://") {
+            let rest = &url[7..];
+            let slash = rest.find('/').ok_or_else(bad)?;
+            if let Some((userinfo, _host)) = rest[..slash].rsplit_once('@') {
+                let name = userinfo.split(';').next().unwrap_or("");
+                user = Some(String::from_utf8(encoding::percent_decode(name)).map_err(|_| bad())?);
+            }
+            &rest[slash..]
+        } else {
+            url
+        };
+        let path = path.strip_prefix('/').ok_or_else(bad)?;
+
+        let mut parts = path.split("/;");
+        let mut mailbox_part = parts.next().ok_or_else(bad)?.split(';');
+        let mailbox = String::from_utf8(encoding::percent_decode(mailbox_part.next().unwrap_or(""))).map_err(|_| bad())?;
+        if mailbox.is_empty() { return Err(bad()) }
+
+        let mut uid_validity = None;
+        for param in mailbox_part {
+            let (key, value) = param.split_once('=').ok_or_else(bad)?;
+            if key.eq_ignore_ascii_case("UIDVALIDITY") {
+                uid_validity = Some(value.parse().map_err(|_| bad())?);
+            }
+        }
+
+        let mut uid = None;
+        let mut section = String::new();
+        let mut partial = None;
+        for part in parts {
+            let (key, value) = part.split_once('=').ok_or_else(bad)?;
+            match key.to_uppercase().as_str() {
+                "UID" => uid = Some(value.trim_end_matches('/').parse().map_err(|_| bad())?),
+                "SECTION" => section = String::from_utf8(encoding::percent_decode(value.trim_end_matches('/'))).map_err(|_| bad())?,
+                "PARTIAL" => {
+                    let mut range = value.splitn(2, '.');
+                    let offset = range.next().unwrap_or("").parse().map_err(|_| bad())?;
+                    let length = match range.next() {
+                        Some(l) => Some(l.parse().map_err(|_| bad())?),
+                        None => None,
+                    };
+                    partial = Some((offset, length));
+                }
+                "URLAUTH" => {}
+                _ => return Err(bad()),
+            }
+        }
+
+        Ok(Self{
+            user,
+            mailbox,
+            uid_validity,
+            uid: uid.ok_or_else(bad)?,
+            section,
+            partial,
+        })
+    }
+}
+
+#[test]
+fn imap_url_full(){
+    let url = ImapUrl::try_from("imap://test@mail.example.scot/Sent%20Items;UIDVALIDITY=1637/;UID=20/;SECTION=1.2/;PARTIAL=0.1024").unwrap();
+    assert_eq!(url, ImapUrl{
+        user: Some("test".into()),
+        mailbox: "Sent Items".into(),
+        uid_validity: Some(1637),
+        uid: 20,
+        section: "1.2".into(),
+        partial: Some((0, Some(1024))),
+    });
+}
+#[test]
+fn imap_url_relative(){
+    let url = ImapUrl::try_from("/INBOX/;UID=3").unwrap();
+    assert_eq!(url.mailbox, "INBOX");
+    assert_eq!(url.uid, 3);
+    assert_eq!(url.section, "");
+    assert!(ImapUrl::try_from("INBOX/;UID=3").is_err());
+}
@@ -1,11 +1,12 @@
 use std::net::{TcpListener, TcpStream};
+use std::time::{Duration, Instant};
 //use native_tls::{Identity, TlsAcceptor, Tls}
 
 mod stream;
-use stream::Stream;
+use stream::{Stream, Chunk};
 
 mod types;
-use types::{Command, Response};
+use types::{Command, Response, Extension};
 
 mod error;
 use error::{Result, Error};
@@ -13,13 +14,48 @@ use error::{Result, Error};
 mod session;
 use session::{UserSession};
 
+mod config;
+use config::Config;
+
 mod email;
 
+mod storage;
+
+mod parser;
+
+mod imapurl;
+
+mod sequence;
+
+mod search;
+use search::SearchResult;
+
+mod encoding;
+
+mod fetch;
+
+mod address;
+
+mod mime;
+
+mod header;
+
+mod changes;
+
+mod notify;
+
 #[cfg(test)]
 mod test;
 
 static BIND_ADDRESS: &str = "0.0.0.0:143";
 static MAX_BAD_ATTEMPTS: u8 = 3;
+/// How long IDLE waits for DONE before looking at the change log again
+static IDLE_POLL: Duration = Duration::from_secs(1);
+/// IDLE rereads the mailbox this often even when no session changed it, to pick up mail dropped into the folder
+static IDLE_RESCAN: Duration = Duration::from_secs(15);
+/// Extensions advertised in CAPABILITY whatever the state of the connection, LITERAL+ covers the LITERAL- IMAP4rev2 asks for
+static EXTENSIONS: &[&str] = &["NAMESPACE", "LITERAL+", "CATENATE", "UIDPLUS", "MOVE", "ESEARCH", "SEARCHRES", "BINARY", "IDLE", "NOTIFY",
+    "CONDSTORE", "QRESYNC", "ENABLE", "UTF8=ACCEPT", "UNSELECT", "LIST-EXTENDED", "LIST-STATUS", "SPECIAL-USE", "STATUS=SIZE"];
 
 /// Main entry point, calls the TCP listener INIT [listen]
 /// 
@@ -32,15 +68,17 @@ fn listen() -> Result<()> {
     println!("Starting IMAP Server...");
     let listener = TcpListener::bind(BIND_ADDRESS).map_err(Error::IO)?;
     println!("Listening on {}", listener.local_addr().map_err(Error::IO)?);
+    let config = Config::load("config.aml");
 
     for stream in listener.incoming() {
         match stream {
             Ok(s) => {
-                std::thread::spawn(|| -> Result<()> {
+                let config = config.clone();
+                std::thread::spawn(move || -> Result<()> {
                     println!("Recieved connection from: {}", &s.peer_addr().map_err(Error::IO)?);
                     s.set_read_timeout(Some(std::time::Duration::from_secs(120))).unwrap();
                     s.set_write_timeout(Some(std::time::Duration::from_secs(15))).unwrap();
-                    imap_main(s)?;
+                    imap_main(s, &config)?;
                     Ok(())
                 });
             },
@@ -49,152 +87,504 @@ fn listen() -> Result<()> {
     }
     Ok(())
 }
-///Function takes the network buffer from the client and splits it into parts
+///Function takes the network buffer from the client and splits it into parts, the arguments stay as bytes
 /// 
-fn parse_response(res: String) -> Result<(Command, Option<String>, String)>{
+fn parse_response(res: Vec<u8>) -> Result<(Command, Option<String>, Vec<u8>)>{
 
-    let tmp = res.replace("\r\n", "").to_owned();
-    let mut split = tmp.splitn(3, " ");
+    // Only the final CRLF is stripped, literals inside the command keep theirs
+    let tmp = res.strip_suffix(b"\r\n").unwrap_or(&res);
+    let mut split = tmp.splitn(3, |&b| b == b' ');
 
-    let tag = Some(split.next().unwrap().to_string());
-    let cmd = Command::try_from(split.next().unwrap().to_string()).unwrap(); 
-    let msg = split.next().unwrap_or("");
+    let tag = Some(String::from_utf8_lossy(split.next().unwrap()).to_string());
+    let cmd = Command::from(String::from_utf8_lossy(split.next().unwrap_or(b"")).to_string());
+    let args = split.next().unwrap_or(b"");
 
-    Ok((cmd, tag, msg.to_owned()))
+    Ok((cmd, tag, args.to_vec()))
+}
+/// Handles COPY and MOVE and their UID forms. MOVE sends COPYUID untagged before the EXPUNGEs as RFC 6851 asks
+///
+fn copy_or_move(stream: &mut Stream, session: &mut UserSession, tag: Option<String>, msg: &str, uid: bool, is_move: bool) -> Result<()> {
+    let name = if is_move { "MOVE" } else { "COPY" };
+    let result = match is_move {
+        true => session.move_messages(msg, uid),
+        false => session.copy(msg, uid).map(|(validity, from, to)| (validity, from, to, Vec::new())),
+    };
+    match result {
+        Ok((_, from, _, _)) if from.is_empty() => {
+            stream.write(tag, Response::Ok, format!("{} completed, no messages matched.\r\n", name))
+        }
+        Ok((uid_validity, from, to, expunged)) if is_move => {
+            stream.write(None, Response::Ok, format!("[COPYUID {} {} {}] Moved\r\n", uid_validity, from, to))?;
+            for response in expunged {
+                stream.write(None, Response::None, format!("{}\r\n", response))?;
+            }
+            stream.write(tag, Response::Ok, "MOVE completed.\r\n".into())
+        }
+        Ok((uid_validity, from, to, _)) => {
+            stream.write(tag, Response::Ok, format!("[COPYUID {} {} {}] COPY completed.\r\n", uid_validity, from, to))
+        }
+        Err(Error::MailboxNotFound) => stream.write(tag, Response::No, "[TRYCREATE] Mailbox does not exist\r\n".into()),
+        Err(Error::ReadOnly) => stream.write(tag, Response::No, "Mailbox is read-only\r\n".into()),
+        Err(e) => stream.write(tag, Response::No, format!("{} error: {:?}\r\n", name, e)),
+    }
+}
+/// Handles SEARCH and UID SEARCH, answering with ESEARCH when the client gave RETURN options. A search using MODSEQ
+/// ends with the highest MODSEQ of the matches (RFC 7162)
+///
+fn search(stream: &mut Stream, session: &mut UserSession, tag: Option<String>, msg: &str, uid: bool) -> Result<()> {
+    match session.search(msg, uid) {
+        Ok(SearchResult{ matches, returns: None, highest_modseq }) => {
+            let results: Vec<String> = matches.iter().map(|n| n.to_string()).collect();
+            let line = match (results.is_empty(), highest_modseq) {
+                (true, _) => "SEARCH\r\n".to_owned(),
+                (false, Some(modseq)) => format!("SEARCH {} (MODSEQ {})\r\n", results.join(" "), modseq),
+                (false, None) => format!("SEARCH {}\r\n", results.join(" ")),
+            };
+            stream.write(None, Response::None, line)?;
+            stream.write(tag, Response::Ok, "SEARCH completed.\r\n".into())
+        }
+        Ok(SearchResult{ matches, returns: Some(returns), highest_modseq }) => {
+            // A SAVE on its own asks for no result at all
+            if returns.iter().any(|r| *r != search::ReturnOption::Save) {
+                let line = search::format_esearch(tag.as_deref().unwrap_or("*"), uid, &matches, &returns, highest_modseq);
+                stream.write(None, Response::None, line)?;
+            }
+            stream.write(tag, Response::Ok, "SEARCH completed.\r\n".into())
+        }
+        Err(Error::BadCharset) => stream.write(tag, Response::No, "[BADCHARSET (UTF-8 US-ASCII)] Charset not supported\r\n".into()),
+        Err(e) => stream.write(tag, Response::Bad, format!("SEARCH error: {:?}\r\n", e)),
+    }
+}
+/// Handles FETCH and UID FETCH, a BINARY fetch of a part we cannot decode is refused with `[UNKNOWN-CTE]` and one too
+/// large to decode with `[LIMIT]`. Responses are written as each message is read so a large fetch never holds more
+/// than one message, a message whose file has gone gets an untagged NO and the rest are still sent
+///
+fn fetch(stream: &mut Stream, session: &mut UserSession, tag: Option<String>, msg: &str, uid: bool) -> Result<()> {
+    let write = |response: Vec<Chunk>| stream.write_chunks(&response);
+    let result = match uid {
+        true => session.fetch_uid(msg, write),
+        false => session.fetch_seq(msg, write),
+    };
+    match result {
+        Ok(()) => stream.write(tag, Response::Ok, "FETCH completed.\r\n".into()),
+        Err(Error::UnknownCte) => stream.write(tag, Response::No, "[UNKNOWN-CTE] Can not decode part\r\n".into()),
+        Err(Error::TooLarge) => stream.write(tag, Response::No, "[LIMIT] Part too large to decode\r\n".into()),
+        Err(Error::IO(e)) => Err(Error::IO(e)),
+        Err(e) => stream.write(tag, Response::Bad, format!("FETCH error: {:?}\r\n", e)),
+    }
+}
+/// Handles STORE and UID STORE, each changed message gets an untagged FETCH with its new flags unless `.SILENT`.
+/// Messages UNCHANGEDSINCE kept from being changed are listed in `[MODIFIED]` (RFC 7162)
+///
+fn store(stream: &mut Stream, session: &mut UserSession, tag: Option<String>, msg: &str, uid: bool) -> Result<()> {
+    match session.store(msg, uid) {
+        Ok((responses, modified)) => {
+            for response in responses {
+                stream.write(None, Response::None, format!("{}\r\n", response))?;
+            }
+            match modified.is_empty() {
+                true => stream.write(tag, Response::Ok, "STORE completed.\r\n".into()),
+                false => stream.write(tag, Response::Ok, format!("[MODIFIED {}] Conditional STORE failed\r\n", sequence::SequenceSet::from_numbers(&modified))),
+            }
+        }
+        Err(Error::ReadOnly) => stream.write(tag, Response::No, "Mailbox is read-only\r\n".into()),
+        Err(e) => stream.write(tag, Response::Bad, format!("STORE error: {:?}\r\n", e)),
+    }
+}
+/// Handles EXPUNGE and UID EXPUNGE, sending an untagged EXPUNGE for every message removed or a VANISHED for them
+/// all with QRESYNC enabled
+///
+fn expunge(stream: &mut Stream, session: &mut UserSession, tag: Option<String>, msg: &str, uid: bool) -> Result<()> {
+    match session.expunge(msg, uid) {
+        Ok(expunged) => {
+            for response in expunged {
+                stream.write(None, Response::None, format!("{}\r\n", response))?;
+            }
+            stream.write(tag, Response::Ok, "EXPUNGE completed.\r\n".into())
+        }
+        Err(Error::ReadOnly) => stream.write(tag, Response::No, "Mailbox is read-only\r\n".into()),
+        Err(e) => stream.write(tag, Response::Bad, format!("EXPUNGE error: {:?}\r\n", e)),
+    }
+}
+/// Sends untagged EXPUNGE, EXISTS, RECENT and FETCH FLAGS for changes to the selected mailbox made elsewhere.
+/// `expunge` is false while answering commands that name messages by sequence number, those numbers are the ones
+/// the client had before the command so nothing may renumber them
+///
+fn send_updates(stream: &mut Stream, session: &mut UserSession, expunge: bool) -> Result<()> {
+    // A mailbox that can no longer be read has nothing to report, the command itself will say what is wrong
+    let Ok(updates) = session.pending_updates(expunge) else { return Ok(()) };
+    for update in updates {
+        stream.write(None, Response::None, format!("{}\r\n", update))?;
+    }
+    Ok(())
+}
+/// Pushes changes to the client until it sends something, looking at the change log every [IDLE_POLL] and rereading
+/// the mailboxes every [IDLE_RESCAN]. With NOTIFY active the events it asked for are sent, otherwise the updates for
+/// the selected mailbox. Returns false when `limit` passed first
+///
+fn push_changes(stream: &mut Stream, session: &mut UserSession, limit: Option<Duration>) -> Result<bool> {
+    let started = Instant::now();
+    let mut version = None;
+    let mut rescanned = Instant::now();
+    loop {
+        let rescan = rescanned.elapsed() >= IDLE_RESCAN;
+        if version != Some(changes::version()) || rescan {
+            version = Some(changes::version());
+            if rescan { rescanned = Instant::now() }
+            match session.notifying() {
+                true => for event in session.notify_events(rescan)? {
+                    stream.write(None, Response::None, format!("{}\r\n", event))?;
+                },
+                false => send_updates(stream, session, true)?,
+            }
+        }
+        if stream.wait_for_data(IDLE_POLL)? { return Ok(true) }
+        if limit.is_some_and(|limit| started.elapsed() >= limit) { return Ok(false) }
+    }
+}
+/// Handles IDLE (RFC 2177), changes to the selected mailbox are pushed as they happen until the client sends DONE.
+/// Returns false when the client idled past the configured limit and has been sent BYE
+///
+fn idle(stream: &mut Stream, session: &mut UserSession, tag: Option<String>, config: &Config) -> Result<bool> {
+    stream.write(None, Response::Continuation, "idling\r\n".into())?;
+    if !push_changes(stream, session, Some(config.idle_timeout))? {
+        stream.write(None, Response::None, "BYE Idle for too long\r\n".into())?;
+        return Ok(false)
+    }
+    match stream.read()?.eq_ignore_ascii_case(b"DONE\r\n") {
+        true => stream.write(tag, Response::Ok, "IDLE terminated.\r\n".into())?,
+        false => stream.write(tag, Response::Bad, "Expected DONE\r\n".into())?,
+    }
+    Ok(true)
+}
+/// Handles NOTIFY (RFC 5465), `STATUS` sends the state of every watched mailbox before the tagged response
+///
+fn notify(stream: &mut Stream, session: &mut UserSession, tag: Option<String>, msg: &str) -> Result<()> {
+    match session.notify(msg) {
+        Ok(statuses) => {
+            for status in statuses {
+                stream.write(None, Response::None, format!("{}\r\n", status))?;
+            }
+            stream.write(tag, Response::Ok, "NOTIFY completed.\r\n".into())
+        }
+        Err(Error::BadEvent) => stream.write(tag, Response::No, format!("[BADEVENT ({})] Event not supported\r\n", notify::SUPPORTED_EVENTS)),
+        Err(e) => stream.write(tag, Response::Bad, format!("NOTIFY error: {:?}\r\n", e)),
+    }
+}
+/// Works out the capabilities for the connection as it stands. Before login the client is told how it may log in,
+/// STARTTLS is only offered on plain connections with a certificate installed and LOGINDISABLED replaces the
+/// mechanisms while login waits for TLS
+///
+fn capabilities(stream: &Stream, session: &UserSession, config: &Config) -> String {
+    let mut capabilities = vec!["IMAP4rev1", "IMAP4rev2"];
+    if !session.authenticated {
+        if !stream.is_secure() && stream::tls_available() { capabilities.push("STARTTLS") }
+        if login_disabled(stream, config) {
+            capabilities.push("LOGINDISABLED");
+        }else{
+            capabilities.extend(["AUTH=PLAIN", "SASL-IR"]);
+        }
+    }
+    capabilities.extend(EXTENSIONS);
+    capabilities.join(" ")
+}
+/// Whether logging in has to wait for STARTTLS, so passwords are never sent in the clear. On by default when a
+/// certificate is installed, `login_requires_tls = false` in config.aml turns it off. Ignored without a certificate
+/// as the client would have no way in
+///
+fn login_disabled(stream: &Stream, config: &Config) -> bool {
+    !stream.is_secure() && stream::tls_available() && config.login_requires_tls
+}
+/// Handles AUTHENTICATE PLAIN, the response comes with the command (SASL-IR) or after a continuation. Returns false
+/// when the exchange failed
+///
+fn authenticate(stream: &mut Stream, session: &mut UserSession, msg: &str) -> Result<bool> {
+    let mut args = msg.split_whitespace();
+    if !args.next().is_some_and(|m| m.eq_ignore_ascii_case("PLAIN")) { return Ok(false) }
+    let response = match args.next() {
+        Some(initial) => initial.to_owned(),
+        None => {
+            stream.write(None, Response::Continuation, "\r\n".into())?;
+            String::from_utf8_lossy(&stream.read()?).trim_end().to_owned()
+        }
+    };
+    Ok(response != "*" && session.authenticate_plain(response.as_bytes()))
 }
 /// Main program Loop, imap logic is here
 /// 
-fn imap_main(stream: TcpStream) -> Result<()> {
+fn imap_main(stream: TcpStream, config: &Config) -> Result<()> {
 
     let mut stream: Stream = Stream::new(stream);
 
-    let mut session = UserSession::new();
+    let mut session = UserSession::new().with_memory_limit(config.session_memory_limit);
 
-    stream.write(None, Response::Ok, "IMAP4 Service Ready.\r\n".into())?;
+    stream.write(None, Response::Ok, format!("[CAPABILITY {}] IMAP4 Service Ready.\r\n", capabilities(&stream, &session, config)))?;
 
+    // Whether the command just handled reports changes and if expunges may be among them
+    let mut updates = None;
     loop{
-        std::thread::sleep(std::time::Duration::from_millis(500));
+        // Changes made by other sessions are reported once a command has run, just before its tagged response
+        if let Some(expunge) = updates.take() { send_updates(&mut stream, &mut session, expunge)?; }
+        stream.release()?;
+        // With NOTIFY active the client hears about changes while it is not sending commands
+        if session.notifying() { push_changes(&mut stream, &mut session, None)?; }
         let res = stream.read()?;
-        let (cmd, tag, msg) = parse_response(res)?;
+        let (cmd, tag, args) = parse_response(res)?;
+        let msg = String::from_utf8_lossy(&args).to_string();
         //println!("CMD: {:?}, TAG: {:?}, MSG: {}", cmd, tag, msg);
 
+        // SELECT starts afresh and IDLE reports changes itself
+        updates = match cmd {
+            Command::Select | Command::Examine | Command::Logout | Command::Idle => None,
+            Command::Fetch | Command::Store | Command::Search | Command::Copy | Command::Move => Some(false),
+            _ => Some(true),
+        };
+        stream.hold_tagged();
+
         match cmd {
             Command::Capability => {
-                stream.write(None, Response::None, "CAPABILITY IMAP4 IMAP4rev1 AUTH=PLAIN\r\n".into())?;
+                stream.write(None, Response::None, format!("CAPABILITY {}\r\n", capabilities(&stream, &session, config)))?;
                 stream.write(tag, Response::Ok, "CAPABILITY completed.\r\n".into())?;
             }
             Command::Noop => {
                 stream.write(tag, Response::Ok, "NOOP COMPLETED\r\n".into())?;
             }
+            Command::Check => {
+                if !session.authenticated { println!("Not Authenticated yet"); continue }
+                stream.write(tag, Response::Ok, "CHECK completed.\r\n".into())?;
+            }
+            Command::StartTls => {
+                if session.authenticated || stream.is_secure() || !stream::tls_available() {
+                    stream.write(tag, Response::Bad, "STARTTLS not available\r\n".into())?;
+                    continue
+                }
+                stream.write(tag, Response::Ok, "Begin TLS negotiation now\r\n".into())?;
+                stream.release()?;
+                stream.start_tls()?;
+            }
             Command::Authenticate => {
-                todo!();
+                if session.authenticated || login_disabled(&stream, config) {
+                    stream.write(tag, Response::No, "[PRIVACYREQUIRED] AUTHENTICATE not allowed now\r\n".into())?;
+                    continue
+                }
+                if authenticate(&mut stream, &mut session, &msg)? {
+                    stream.write(tag, Response::Ok, format!("[CAPABILITY {}] AUTHENTICATE completed.\r\n", capabilities(&stream, &session, config)))?;
+                    session.count_emails()?;
+                }else{
+                    stream.write(tag, Response::No, "[AUTHENTICATIONFAILED] AUTHENTICATE failed.\r\n".into())?;
+                }
             }
             Command::Login => {
+                if login_disabled(&stream, config) {
+                    stream.write(tag, Response::No, "[PRIVACYREQUIRED] LOGIN disabled until STARTTLS\r\n".into())?;
+                    continue
+                }
                 session.authenticate(&msg);
-                println!("{:?}", session);
                 if session.authenticated {
-                    stream.write(tag, Response::Ok, "LOGIN completed.\r\n".into())?;
+                    stream.write(tag, Response::Ok, format!("[CAPABILITY {}] LOGIN completed.\r\n", capabilities(&stream, &session, config)))?;
                     session.count_emails()?;
                 }else{
-                    stream.write(tag, Response::Bad, "LOGIN failed.\r\n".into())?;
+                    stream.write(tag, Response::No, "[AUTHENTICATIONFAILED] LOGIN failed.\r\n".into())?;
                 }
             }
             Command::List => {
                 if !session.authenticated { println!("Not Authenticated yet"); continue }
-                let folders = session.list(&msg).unwrap();
-                for folder in folders{
-                    if folder == r#""""#{
-                        stream.write(None, Response::None, format!("LIST (\\Noselect \\HasChildren) \"/\" {}\r\n", folder))?;
-                    }else{
-                        stream.write(None, Response::None, format!("LIST (\\Marked \\HasNoChildren) \"/\" {}\r\n", folder))?;
+                match session.list(&msg) {
+                    Ok(responses) => {
+                        for response in responses {
+                            stream.write(None, Response::None, format!("{}\r\n", response))?;
+                        }
+                        stream.write(tag, Response::Ok, "LIST completed.\r\n".into())?;
                     }
+                    Err(e) => stream.write(tag, Response::Bad, format!("LIST error: {:?}\r\n", e))?,
                 }
-                stream.write(tag, Response::Ok, "LIST completed.\r\n".into())?;
-            }
-            Command::Select => {
-                if !session.authenticated { println!("Not Authenticated yet"); continue }
-                let mailbox = msg.split(" ").last().unwrap().to_uppercase().replace("\"", "");
-                match mailbox.as_str() {
-                    "INBOX" => {
-                        stream.write(None, Response::None, format!("{} EXISTS\r\n", session.mail_count))?; // Number of mail items
-                        stream.write(None, Response::None, format!("{} RECENT\r\n", session.mail_count))?; // Number of unread
-                        //stream.write(None, Response::None, format!("{} RECENT\r\n", "0"))?; // Number of unread
-                        stream.write(None, Response::None, "FLAGS (\\Seen \\Answered \\Flagged \\Deleted \\Draft)\r\n".into())?;
-                        stream.write(None, Response::Ok, "[PERMANENTFLAGS (\\Seen \\Answered \\Flagged \\Deleted \\Draft)] Permanent flags\r\n".into())?;
-                        stream.write(None, Response::Ok, "[UIDVALIDITY 1]\r\n".into())?;
-                        stream.write(None, Response::Ok, format!("[UNSEEN {}]\r\n", session.mail_count))?;
-                        //stream.write(None, Response::Ok, format!("[UIDNEXT {}] The next unique identifier value\r\n", session.mail_count))?;
+            }
+            Command::Select | Command::Examine => {
+                if !session.authenticated { println!("Not Authenticated yet"); continue }
+                let examine = matches!(cmd, Command::Examine);
+                let name = if examine { "EXAMINE" } else { "SELECT" };
+                let closes = session.closes_selected();
+                let selected = match examine {
+                    true => session.examine(&msg),
+                    false => session.select(&msg),
+                };
+                let (mailbox, resync) = match selected {
+                    Ok(selected) => selected,
+                    Err(Error::BadArguments(e)) => {
+                        stream.write(tag, Response::Bad, format!("{} error: {}\r\n", name, e))?;
+                        continue
                     }
-                    _=>{
-                        todo!()
+                    Err(Error::MailboxNotFound) => {
+                        stream.write(tag, Response::No, "[NONEXISTENT] Mailbox does not exist\r\n".into())?;
+                        continue
                     }
+                    Err(e) => {
+                        stream.write(tag, Response::No, format!("[SERVERBUG] {} error: {:?}\r\n", name, e))?;
+                        continue
+                    }
+                };
+                // IMAP4rev2 dropped RECENT and the UNSEEN response code
+                let rev2 = session.is_enabled(Extension::Imap4Rev2);
+                if closes { stream.write(None, Response::Ok, "[CLOSED] Previous mailbox closed\r\n".into())?; }
+                stream.write(None, Response::None, format!("{} EXISTS\r\n", session.mail_count))?; // Number of mail items
+                if !rev2 { stream.write(None, Response::None, "0 RECENT\r\n".into())?; } // Messages arriving from now on are recent to this session
+                //stream.write(None, Response::None, format!("{} RECENT\r\n", "0"))?; // Number of unread
+                stream.write(None, Response::None, "FLAGS (\\Seen \\Answered \\Flagged \\Deleted \\Draft)\r\n".into())?;
+                match examine {
+                    true => stream.write(None, Response::Ok, "[PERMANENTFLAGS ()] No permanent flags permitted\r\n".into())?,
+                    false => stream.write(None, Response::Ok, "[PERMANENTFLAGS (\\Seen \\Answered \\Flagged \\Deleted \\Draft)] Permanent flags\r\n".into())?,
+                }
+                stream.write(None, Response::Ok, format!("[UIDVALIDITY {}]\r\n", mailbox.uid_validity))?;
+                // The sequence number of the first message without \Seen, left out when every message has been seen
+                let unseen = mailbox.messages.iter().position(|m| !m.flags.iter().any(|f| f.eq_ignore_ascii_case("\\Seen")));
+                if let (false, Some(index)) = (rev2, unseen) {
+                    stream.write(None, Response::Ok, format!("[UNSEEN {}] First unseen message\r\n", index + 1))?;
+                }
+                stream.write(None, Response::Ok, format!("[UIDNEXT {}] The next unique identifier value\r\n", mailbox.uid_next))?;
+                stream.write(None, Response::Ok, format!("[HIGHESTMODSEQ {}] Highest\r\n", mailbox.highest_modseq))?;
+                for response in resync {
+                    stream.write(None, Response::None, format!("{}\r\n", response))?;
+                }
+                match examine {
+                    true => stream.write(tag, Response::Ok, "[READ-ONLY] EXAMINE completed.\r\n".into())?,
+                    false => stream.write(tag, Response::Ok, "[READ-WRITE] SELECT completed.\r\n".into())?,
                 }
-                stream.write(tag, Response::Ok, "[READ-WRITE] SELECT completed.\r\n".into())?;
             }
             Command::Lsub => {
                 stream.write(tag, Response::Ok, "LSUB completed.\r\n".into())?;
             }
             Command::Status => {
                 if !session.authenticated { println!("Not Authenticated yet"); continue }
-                todo!();
-                // stream.write(None, Response::None, 
-                //     format!("STATUS Inbox (UNSEEN 1 MESSAGES {} RECENT 1 UIDNEXT {} UIDVALIDITY 999)\r\n", session.mail_count, session.mail_count+1))?;
-                // stream.write(tag, Response::Ok, "STATUS completed.\r\n".into())?;
+                match session.status(&msg) {
+                    Ok(status) => {
+                        stream.write(None, Response::None, format!("{}\r\n", status))?;
+                        stream.write(tag, Response::Ok, "STATUS completed.\r\n".into())?;
+                    }
+                    Err(Error::MailboxNotFound) => stream.write(tag, Response::No, "[NONEXISTENT] Mailbox does not exist\r\n".into())?,
+                    Err(e) => stream.write(tag, Response::Bad, format!("STATUS error: {:?}\r\n", e))?,
+                }
             }
             Command::Fetch => {
                 if !session.authenticated { println!("Not Authenticated yet"); continue }
-                // Client asking for full message
-                let responses = session.fetch_seq(&msg).unwrap();
-                for response in responses{
-                    stream.write(None, Response::None, response)?;
-                }
-                stream.write(tag, Response::Ok, "FETCH completed.\r\n".into())?; 
+                if !session.has_selected() { stream.write(tag, Response::Bad, "No mailbox selected\r\n".into())?; continue }
+                fetch(&mut stream, &mut session, tag, &msg, false)?;
             }
             Command::Create => {
                 if !session.authenticated { println!("Not Authenticated yet"); continue }
-                stream.write(tag, Response::No, "Not implemented\r\n".into())?;
+                match session.mailbox_exists(&msg) {
+                    Ok(true) => stream.write(tag, Response::No, "[ALREADYEXISTS] Mailbox already exists\r\n".into())?,
+                    Ok(false) => stream.write(tag, Response::No, "[CANNOT] Mailboxes are created on the server\r\n".into())?,
+                    Err(e) => stream.write(tag, Response::Bad, format!("CREATE error: {:?}\r\n", e))?,
+                }
             }
             Command::Uid => {
                 if !session.authenticated { println!("Not Authenticated yet"); continue }
+                if !session.has_selected() { stream.write(tag, Response::Bad, "No mailbox selected\r\n".into())?; continue }
                 let mut split = msg.splitn(2, " ");
-                let cmd = split.next().unwrap();
-                let msg = split.next().unwrap();
-                match cmd {
-                    "SEARCH" => {
-                        let uids = session.search(&msg).unwrap();
-                        let mut uid_string = String::new();
-                        for uid in uids{
-                            uid_string.push_str(&(uid.to_owned()+" "));
-                        }
-                        stream.write(None, Response::None, format!("SEARCH {}\r\n", uid_string))?;
-                        stream.write(tag, Response::Ok, "Search completed\r\n".into())?;
-                    }
-                    "FETCH" => { 
-                        let responses = session.fetch_uid(&msg).unwrap();
-                        for response in responses{
-                            stream.write(None, Response::None, response)?;
-                        }
-                        stream.write(tag, Response::Ok, "FETCH completed.\r\n".into())?; 
-                    },
-                    "COPY" => {
-                        match session.copy(msg) {
-                            Ok(_) => stream.write(tag, Response::Ok, "COPY Completed\r\n".into())?,
-                            Err(e) => stream.write(tag, Response::No, format!("COPY error: {:?}\r\n",e))?,
-                        }
-                             
-                    }
+                let cmd = split.next().unwrap().to_uppercase();
+                let msg = split.next().unwrap_or("");
+                match cmd.as_str() {
+                    "SEARCH" => search(&mut stream, &mut session, tag, msg, true)?,
+                    "FETCH" => fetch(&mut stream, &mut session, tag, msg, true)?,
+                    "COPY" => copy_or_move(&mut stream, &mut session, tag, msg, true, false)?,
+                    "MOVE" => copy_or_move(&mut stream, &mut session, tag, msg, true, true)?,
+                    "STORE" => store(&mut stream, &mut session, tag, msg, true)?,
+                    "EXPUNGE" => expunge(&mut stream, &mut session, tag, msg.trim(), true)?,
                     _ => {
                         stream.write(tag, Response::Ok, "FETCH Completed\r\n".into())?;
                     }
                 }
             }
-            Command::Subscribe => {
+            Command::Append => {
+                if !session.authenticated { println!("Not Authenticated yet"); continue }
+                match session.append(&args) {
+                    Ok((uid_validity, uid)) => stream.write(tag, Response::Ok, format!("[APPENDUID {} {}] APPEND completed.\r\n", uid_validity, uid))?,
+                    Err(Error::BadUrl(url)) => stream.write(tag, Response::No, format!("[BADURL {}] CATENATE URL could not be resolved\r\n", url))?,
+                    Err(Error::MailboxNotFound) => stream.write(tag, Response::No, "[TRYCREATE] Mailbox does not exist\r\n".into())?,
+                    Err(e) => stream.write(tag, Response::Bad, format!("APPEND error: {:?}\r\n", e))?,
+                }
+            }
+            Command::Search => {
+                if !session.authenticated { println!("Not Authenticated yet"); continue }
+                if !session.has_selected() { stream.write(tag, Response::Bad, "No mailbox selected\r\n".into())?; continue }
+                search(&mut stream, &mut session, tag, &msg, false)?;
+            }
+            Command::Copy => {
+                if !session.authenticated { println!("Not Authenticated yet"); continue }
+                if !session.has_selected() { stream.write(tag, Response::Bad, "No mailbox selected\r\n".into())?; continue }
+                copy_or_move(&mut stream, &mut session, tag, &msg, false, false)?;
+            }
+            Command::Move => {
+                if !session.authenticated { println!("Not Authenticated yet"); continue }
+                if !session.has_selected() { stream.write(tag, Response::Bad, "No mailbox selected\r\n".into())?; continue }
+                copy_or_move(&mut stream, &mut session, tag, &msg, false, true)?;
+            }
+            Command::Store => {
+                if !session.authenticated { println!("Not Authenticated yet"); continue }
+                if !session.has_selected() { stream.write(tag, Response::Bad, "No mailbox selected\r\n".into())?; continue }
+                store(&mut stream, &mut session, tag, &msg, false)?;
+            }
+            Command::Expunge => {
+                if !session.authenticated { println!("Not Authenticated yet"); continue }
+                if !session.has_selected() { stream.write(tag, Response::Bad, "No mailbox selected\r\n".into())?; continue }
+                expunge(&mut stream, &mut session, tag, &msg, false)?;
+            }
+            Command::Idle => {
+                if !session.authenticated { println!("Not Authenticated yet"); continue }
+                if !idle(&mut stream, &mut session, tag, config)? {
+                    let _ = stream.shutdown();
+                    break
+                }
+            }
+            Command::Notify => {
+                if !session.authenticated { println!("Not Authenticated yet"); continue }
+                notify(&mut stream, &mut session, tag, &msg)?;
+            }
+            Command::Enable => {
+                if !session.authenticated { println!("Not Authenticated yet"); continue }
+                match session.enable(&msg) {
+                    Ok(enabled) => {
+                        stream.write(None, Response::None, format!("ENABLED{}\r\n", enabled.iter().map(|e| format!(" {}", e)).collect::<String>()))?;
+                        stream.write(tag, Response::Ok, "ENABLE completed.\r\n".into())?;
+                    }
+                    Err(e) => stream.write(tag, Response::Bad, format!("ENABLE error: {:?}\r\n", e))?,
+                }
+            }
+            Command::Subscribe | Command::Unsubscribe => {
+                if !session.authenticated { println!("Not Authenticated yet"); continue }
+                let subscribe = matches!(cmd, Command::Subscribe);
+                let name = if subscribe { "SUBSCRIBE" } else { "UNSUBSCRIBE" };
+                match session.subscribe(&msg, subscribe) {
+                    Ok(()) => stream.write(tag, Response::Ok, format!("{} Completed\r\n", name))?,
+                    Err(Error::BadArguments(e)) => stream.write(tag, Response::Bad, format!("{} error: {}\r\n", name, e))?,
+                    Err(e) => stream.write(tag, Response::No, format!("[SERVERBUG] {} error: {:?}\r\n", name, e))?,
+                }
+            }
+            Command::Unselect => {
+                if !session.authenticated { println!("Not Authenticated yet"); continue }
+                match session.unselect() {
+                    Ok(()) => stream.write(tag, Response::Ok, "UNSELECT completed.\r\n".into())?,
+                    Err(e) => stream.write(tag, Response::Bad, format!("UNSELECT error: {:?}\r\n", e))?,
+                }
+            }
+            Command::Close => {
+                if !session.authenticated { println!("Not Authenticated yet"); continue }
+                if !session.has_selected() { stream.write(tag, Response::Bad, "No mailbox selected\r\n".into())?; continue }
+                match session.close() {
+                    Ok(()) => stream.write(tag, Response::Ok, "CLOSE completed.\r\n".into())?,
+                    Err(e) => stream.write(tag, Response::No, format!("CLOSE error: {:?}\r\n", e))?,
+                }
+            }
+            Command::Namespace => {
                 if !session.authenticated { println!("Not Authenticated yet"); continue }
-                stream.write(tag, Response::Ok, "SUBSCRIBE Completed\r\n".into())?;
+                // Every folder is the user's own, at the top level with "/" between levels (RFC 2342)
+                stream.write(None, Response::None, "NAMESPACE ((\"\" \"/\")) NIL NIL\r\n".into())?;
+                stream.write(tag, Response::Ok, "NAMESPACE completed.\r\n".into())?;
             }
             Command::Logout => {
                 stream.write(None, Response::None, "BYE\r\n".into())?;
                 stream.write(tag, Response::Ok, "LOGOUT completed.\r\n".into())?;
+                stream.release()?;
                 break
             }
             _ => { 
@@ -202,6 +592,7 @@ fn imap_main(stream: TcpStream) -> Result<()> {
                 println!("{:?} found", cmd);
                 stream.write(tag, Response::Bad, format!("Command Unrecognised, Attempts Remaining: {}\r\n", (MAX_BAD_ATTEMPTS - session.bad_attempts)))?;
                 if session.bad_attempts > 3 { 
+                    stream.release()?;
                     let _ = stream.shutdown();
                     break;
                 }
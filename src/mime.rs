@@ -0,0 +1,421 @@
+//! MIME structure of a message, the part tree behind BODY, BODYSTRUCTURE and section numbers. Parts refer back
+//! into the message by offset so a section can be served without copying it out first
+//!
+use std::ops::Range;
+use std::borrow::Cow;
+use crate::fetch::{imap_string, imap_nstring};
+use crate::encoding;
+use crate::header::HeaderMap;
+
+/// How deeply multiparts and encapsulated messages are followed, parts below this are treated as text/plain
+///
+const MAX_DEPTH: usize = 32;
+
+/// Most of a header block read while parsing, anything after this is treated as body
+///
+const MAX_HEADER: usize = 256 * 1024;
+
+/// How much of a body is read at a time while counting its lines and looking for boundaries
+///
+const CHUNK: usize = 64 * 1024;
+
+/// How much of the start of a line is kept while looking for boundaries, delimiters are at most 72 bytes
+///
+const LINE_PREFIX: usize = 1024;
+
+/// Where the bytes of a message are read from while parsing, a buffer in memory or the file of a large message
+///
+pub trait Source{
+    fn size(&self) -> usize;
+    /// The bytes in `range`, fewer when the source could not supply them all
+    fn read(&self, range: Range<usize>) -> Cow<'_, [u8]>;
+}
+
+impl Source for [u8]{
+    fn size(&self) -> usize {
+        self.len()
+    }
+    fn read(&self, range: Range<usize>) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self[range])
+    }
+}
+
+/// A structured header value such as Content-Type or Content-Disposition, the value and its `;` parameters
+///
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct HeaderValue{
+    pub value: String,
+    pub params: Vec<(String, String)>,
+}
+
+impl HeaderValue{
+    /// Parses `value; name=value; name="quoted; value"`, semicolons inside quotes do not split
+    ///
+    pub fn parse(raw: &str) -> Self {
+        let mut fields = Vec::new();
+        let mut current = String::new();
+        let mut quoted = false;
+        let mut chars = raw.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => quoted = !quoted,
+                '\\' if quoted => current.extend(chars.next()),
+                ';' if !quoted => fields.push(std::mem::take(&mut current)),
+                _ => current.push(c),
+            }
+        }
+        fields.push(current);
+
+        let mut fields = fields.into_iter();
+        let value = fields.next().unwrap_or_default().trim().to_owned();
+        let params = fields
+            .filter_map(|field| field.split_once('=').map(|(k, v)| (k.trim().to_owned(), v.trim().to_owned())))
+            .filter(|(k, _)| !k.is_empty())
+            .collect();
+        Self{ value, params }
+    }
+    /// Looks up a parameter, names are case insensitive and RFC 2231 continuations and charsets are decoded
+    ///
+    pub fn param(&self, name: &str) -> Option<String> {
+        encoding::decode_parameter(&self.params, name)
+    }
+}
+
+/// One node of the MIME tree. Multiparts hold their children in `parts` and message/rfc822 holds the
+/// encapsulated message in `message`
+///
+#[derive(Debug, Clone)]
+pub struct Part{
+    /// Header block including the blank line that ends it
+    pub header: Range<usize>,
+    pub body: Range<usize>,
+    pub headers: HeaderMap,
+    pub mime_type: String,
+    pub subtype: String,
+    pub content_type: HeaderValue,
+    pub id: Option<String>,
+    pub description: Option<String>,
+    pub encoding: String,
+    pub md5: Option<String>,
+    pub disposition: Option<HeaderValue>,
+    pub language: Vec<String>,
+    pub location: Option<String>,
+    pub lines: usize,
+    pub parts: Vec<Part>,
+    pub message: Option<Box<Part>>,
+}
+
+impl Part{
+    /// Parses a whole message into its part tree, only the header blocks are held while the bodies are read a chunk
+    /// at a time
+    ///
+    pub fn parse(data: &(impl Source + ?Sized)) -> Self {
+        Self::parse_range(data, 0..data.size(), false, 0)
+    }
+    /// Parses the entity in `range`, parts of a multipart/digest default to message/rfc822 rather than text/plain.
+    /// `depth` counts the parts above this one, past [MAX_DEPTH] the part is a leaf whatever its Content-Type says
+    ///
+    fn parse_range(data: &(impl Source + ?Sized), range: Range<usize>, digest: bool, depth: usize) -> Self {
+        let head = read_header(data, range.clone());
+        let header = range.start..range.start + header_length(&head);
+        let body = header.end..range.end;
+        let headers = HeaderMap::parse(&head[..header.len()]);
+        let first = |name: &str| headers.get(name).map(|v| v.to_owned());
+
+        let content_type = match first("Content-Type") {
+            Some(value) if depth < MAX_DEPTH => HeaderValue::parse(&value),
+            None if digest && depth < MAX_DEPTH => HeaderValue{ value: "message/rfc822".to_owned(), params: Vec::new() },
+            _ => HeaderValue{ value: "text/plain".to_owned(), params: vec![("charset".to_owned(), "us-ascii".to_owned())] },
+        };
+        let (mime_type, subtype) = match content_type.value.split_once('/') {
+            Some((t, s)) => (t.trim().to_ascii_lowercase(), s.trim().to_ascii_lowercase()),
+            None => ("text".to_owned(), "plain".to_owned()),
+        };
+        let language = first("Content-Language")
+            .map(|l| l.split(',').map(|l| l.trim().to_owned()).filter(|l| !l.is_empty()).collect())
+            .unwrap_or_default();
+
+        let mut part = Self{
+            lines: count_lines(data, body.clone()),
+            id: first("Content-ID"),
+            description: first("Content-Description"),
+            encoding: first("Content-Transfer-Encoding").unwrap_or("7BIT".to_owned()),
+            md5: first("Content-MD5"),
+            disposition: first("Content-Disposition").map(|d| HeaderValue::parse(&d)),
+            location: first("Content-Location"),
+            parts: Vec::new(),
+            message: None,
+            header,
+            body,
+            headers,
+            mime_type,
+            subtype,
+            content_type,
+            language,
+        };
+        if part.mime_type == "multipart" {
+            if let Some(boundary) = part.content_type.param("boundary") {
+                let digest = part.subtype == "digest";
+                part.parts = split_multipart(data, part.body.clone(), &boundary).into_iter()
+                    .map(|range| Self::parse_range(data, range, digest, depth + 1))
+                    .collect();
+            }
+        } else if part.mime_type == "message" && part.subtype == "rfc822" {
+            part.message = Some(Box::new(Self::parse_range(data, part.body.clone(), false, depth + 1)));
+        }
+        part
+    }
+    /// The text parts under this one in order, the leaves BODY and TEXT searches look through. Encapsulated messages
+    /// are walked into and a multipart without a usable boundary counts as text
+    ///
+    pub fn text_parts(&self) -> Vec<&Part> {
+        if self.is_multipart() { return self.parts.iter().flat_map(|p| p.text_parts()).collect() }
+        match &self.message {
+            Some(message) => message.text_parts(),
+            None if self.mime_type == "text" || self.mime_type == "multipart" => vec![self],
+            None => Vec::new(),
+        }
+    }
+    /// A multipart that actually has parts, one without a usable boundary is described as a single part
+    ///
+    pub fn is_multipart(&self) -> bool {
+        self.mime_type == "multipart" && !self.parts.is_empty()
+    }
+    /// Finds the part a section number such as `2.1` refers to. Numbers after a message/rfc822 part count the parts of
+    /// the encapsulated message, and a part that is not a multipart only has part 1, itself
+    ///
+    pub fn find(&self, path: &[usize]) -> Option<&Part> {
+        let mut part = self;
+        for (i, &number) in path.iter().enumerate() {
+            if i > 0 {
+                if let Some(message) = &part.message { part = message }
+            }
+            if part.is_multipart() {
+                part = part.parts.get(number.checked_sub(1)?)?;
+            } else if number != 1 {
+                return None
+            }
+        }
+        Some(part)
+    }
+    /// Formats the part as BODYSTRUCTURE, or as BODY when `extended` is false and the extension data is left off.
+    /// `utf8` is passed on to the ENVELOPE of message/rfc822 parts
+    ///
+    pub fn structure(&self, extended: bool, utf8: bool) -> String {
+        let mut out = String::from("(");
+        if self.is_multipart() {
+            for part in &self.parts {
+                out.push_str(&part.structure(extended, utf8));
+            }
+            out.push_str(&format!(" {}", imap_string(&self.subtype.to_uppercase())));
+            if extended {
+                out.push_str(&format!(" {} {} {} {}", format_params(&self.content_type.params),
+                    self.format_disposition(), self.format_language(), imap_nstring(self.location.as_deref())));
+            }
+        } else {
+            out.push_str(&format!("{} {} {} {} {} {} {}",
+                imap_string(&self.mime_type.to_uppercase()), imap_string(&self.subtype.to_uppercase()),
+                format_params(&self.content_type.params), imap_nstring(self.id.as_deref()),
+                imap_nstring(self.description.as_deref()), imap_string(&self.encoding.to_uppercase()),
+                self.body.len()));
+            if let Some(message) = &self.message {
+                out.push_str(&format!(" {} {} {}", crate::email::envelope(&message.headers, utf8),
+                    message.structure(extended, utf8), self.lines));
+            } else if self.mime_type == "text" {
+                out.push_str(&format!(" {}", self.lines));
+            }
+            if extended {
+                out.push_str(&format!(" {} {} {} {}", imap_nstring(self.md5.as_deref()),
+                    self.format_disposition(), self.format_language(), imap_nstring(self.location.as_deref())));
+            }
+        }
+        out.push(')');
+        out
+    }
+    fn format_disposition(&self) -> String {
+        match &self.disposition {
+            Some(d) => format!("({} {})", imap_string(&d.value.to_uppercase()), format_params(&d.params)),
+            None => "NIL".to_owned(),
+        }
+    }
+    fn format_language(&self) -> String {
+        match self.language.as_slice() {
+            [] => "NIL".to_owned(),
+            [language] => imap_string(language),
+            languages => format!("({})", languages.iter().map(|l| imap_string(l)).collect::<Vec<_>>().join(" ")),
+        }
+    }
+}
+
+/// Length of the header block at the start of `text` including the blank line, all of it when there is no body
+///
+pub fn header_length(text: &[u8]) -> usize {
+    if text.starts_with(b"\r\n") { return 2 }
+    if text.starts_with(b"\n") { return 1 }
+    find(text, b"\r\n\r\n").map(|i| i + 4)
+        .or_else(|| find(text, b"\n\n").map(|i| i + 2))
+        .unwrap_or(text.len())
+}
+
+/// The start of `range` as far as the blank line ending its header block, read [CHUNK] bytes at a time and never
+/// more than [MAX_HEADER]
+///
+fn read_header(data: &(impl Source + ?Sized), range: Range<usize>) -> Vec<u8> {
+    let end = range.end.min(range.start + MAX_HEADER);
+    let mut head = Vec::new();
+    while range.start + head.len() < end {
+        let start = range.start + head.len();
+        let piece = data.read(start..end.min(start + CHUNK));
+        if piece.is_empty() { break }
+        head.extend_from_slice(&piece);
+        if head.starts_with(b"\n") || head.starts_with(b"\r\n") || find(&head, b"\n\n").is_some() || find(&head, b"\r\n\r\n").is_some() { break }
+    }
+    head
+}
+
+/// Position of the first occurrence of `needle` in `haystack`
+///
+pub fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Number of lines in a body, a last line without a line break still counts
+///
+fn count_lines(data: &(impl Source + ?Sized), body: Range<usize>) -> usize {
+    let mut lines = 0;
+    for_each_line(data, body, |_, _, _| {
+        lines += 1;
+        true
+    });
+    lines
+}
+
+/// Calls `f` with each line in `range`, its length without the line break and its first [LINE_PREFIX] bytes
+/// minus the line break, until `f` returns false. [CHUNK] bytes are read at a time so a body is never held whole
+///
+fn for_each_line(data: &(impl Source + ?Sized), range: Range<usize>, mut f: impl FnMut(Range<usize>, usize, &[u8]) -> bool) {
+    let mut start = range.start;
+    let mut offset = range.start;
+    let mut prefix = Vec::new();
+    // Line break bytes at the end of what has been read of the current line
+    let mut ending = 0;
+    while offset < range.end {
+        let chunk = data.read(offset..range.end.min(offset + CHUNK));
+        if chunk.is_empty() { break }
+        for piece in chunk.split_inclusive(|&b| b == b'\n') {
+            let room = LINE_PREFIX.saturating_sub(prefix.len());
+            prefix.extend_from_slice(&piece[..piece.len().min(room)]);
+            let trailing = piece.iter().rev().take_while(|&&b| b == b'\r' || b == b'\n').count();
+            ending = if trailing == piece.len() { ending + trailing } else { trailing };
+            offset += piece.len();
+            if piece.ends_with(b"\n") {
+                let content_len = offset - start - ending;
+                prefix.truncate(content_len);
+                if !f(start..offset, content_len, &prefix) { return }
+                prefix.clear();
+                start = offset;
+                ending = 0;
+            }
+        }
+    }
+    if start < offset {
+        prefix.truncate(offset - start - ending);
+        f(start..offset, offset - start - ending, &prefix);
+    }
+}
+
+/// Finds the parts between boundary delimiters. The line break before a delimiter belongs to the delimiter,
+/// and a missing close delimiter ends the last part at the end of the body
+///
+fn split_multipart(data: &(impl Source + ?Sized), body: Range<usize>, boundary: &str) -> Vec<Range<usize>> {
+    let delimiter = format!("--{}", boundary);
+    let mut ranges = Vec::new();
+    let mut part_start: Option<usize> = None;
+    let mut previous_line_end = body.start;
+    let mut closed = false;
+    for_each_line(data, body.clone(), |line, content_len, content| {
+        if let Some(rest) = content.strip_prefix(delimiter.as_bytes()) {
+            let rest = String::from_utf8_lossy(rest);
+            let rest = rest.trim_end();
+            if rest.is_empty() || rest == "--" {
+                if let Some(start) = part_start {
+                    ranges.push(start..previous_line_end.max(start));
+                }
+                closed = rest == "--";
+                part_start = Some(line.end);
+            }
+        }
+        previous_line_end = line.start + content_len;
+        !closed
+    });
+    if closed { return ranges }
+    if let Some(start) = part_start {
+        ranges.push(start..body.end);
+    }
+    ranges
+}
+
+/// Formats parameters as an IMAP parenthesised list of name value pairs, `NIL` when there are none
+///
+fn format_params(params: &[(String, String)]) -> String {
+    if params.is_empty() { return "NIL".to_owned() }
+    let pairs: Vec<String> = params.iter()
+        .map(|(k, v)| format!("{} {}", imap_string(&k.to_uppercase()), imap_string(v)))
+        .collect();
+    format!("({})", pairs.join(" "))
+}
+
+#[cfg(test)]
+static MULTIPART: &[u8] = b"From: a@b.com\r\nContent-Type: multipart/mixed; boundary=\"XX;1\"\r\n\r\n\
+    preamble\r\n--XX;1\r\nContent-Type: text/plain; charset=utf-8\r\n\r\nHello\r\nthere\r\n\
+    --XX;1\r\nContent-Type: application/pdf; name=a.pdf\r\nContent-Disposition: attachment; filename=\"a.pdf\"\r\n\
+    Content-Transfer-Encoding: base64\r\n\r\nJVBERg==\r\n\
+    --XX;1\r\nContent-Type: message/rfc822\r\n\r\nSubject: Inner\r\n\r\nInner body\r\n--XX;1--\r\nepilogue\r\n";
+
+#[test]
+fn mime_parse_tree(){
+    let part = Part::parse(MULTIPART);
+    assert_eq!(part.parts.len(), 3);
+    assert_eq!(&MULTIPART[part.parts[0].body.clone()], b"Hello\r\nthere");
+    assert_eq!(part.parts[1].disposition.as_ref().unwrap().param("FILENAME").as_deref(), Some("a.pdf"));
+    let inner = part.parts[2].message.as_ref().unwrap();
+    assert_eq!(&MULTIPART[inner.body.clone()], b"Inner body");
+    assert_eq!(HeaderValue::parse("text/plain; charset=\"a;b\"").param("charset").as_deref(), Some("a;b"));
+}
+#[test]
+fn mime_parse_in_pieces(){
+    /// Hands out at most 3 bytes per read, as a file might near its end
+    struct Trickle(&'static [u8]);
+    impl Source for Trickle{
+        fn size(&self) -> usize { self.0.len() }
+        fn read(&self, range: Range<usize>) -> Cow<'_, [u8]> { Cow::Borrowed(&self.0[range.start..range.end.min(range.start + 3)]) }
+    }
+    let whole = Part::parse(MULTIPART);
+    let pieces = Part::parse(&Trickle(MULTIPART));
+    assert_eq!(pieces.structure(true, false), whole.structure(true, false));
+    assert_eq!(pieces.parts[2].message.as_ref().unwrap().body, whole.parts[2].message.as_ref().unwrap().body);
+}
+#[test]
+fn mime_depth_limit(){
+    let data = [&b"Content-Type: message/rfc822\r\n\r\n".repeat(1000)[..], b"Deep"].concat();
+    let mut part = &Part::parse(&data[..]);
+    let mut depth = 0;
+    while let Some(message) = &part.message {
+        part = message;
+        depth += 1;
+    }
+    assert_eq!(depth, MAX_DEPTH);
+    assert_eq!((part.mime_type.as_str(), part.subtype.as_str()), ("text", "plain"));
+}
+#[test]
+fn mime_body_structure(){
+    let part = Part::parse(MULTIPART);
+    assert_eq!(part.parts[0].structure(true, false),
+        "(\"TEXT\" \"PLAIN\" (\"CHARSET\" \"utf-8\") NIL NIL \"7BIT\" 12 2 NIL NIL NIL NIL)");
+    assert_eq!(part.parts[1].structure(true, false),
+        "(\"APPLICATION\" \"PDF\" (\"NAME\" \"a.pdf\") NIL NIL \"BASE64\" 8 NIL (\"ATTACHMENT\" (\"FILENAME\" \"a.pdf\")) NIL NIL)");
+    let body = part.structure(false, false);
+    assert!(body.starts_with("((\"TEXT\" \"PLAIN\" (\"CHARSET\" \"utf-8\") NIL NIL \"7BIT\" 12 2)"));
+    assert!(body.contains("\"MESSAGE\" \"RFC822\" NIL NIL NIL \"7BIT\" 28 (NIL \"Inner\" "));
+    assert!(body.ends_with(" \"MIXED\")"));
+}
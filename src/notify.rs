@@ -0,0 +1,157 @@
+//! NOTIFY (RFC 5465) event subscriptions. A client names groups of mailboxes and the events it wants for each, the
+//! session then reports them from the change log without being asked, even between commands
+//!
+use crate::error::{Result, Error};
+use crate::parser::Arg;
+
+/// The events this server can report, others given to NOTIFY SET are refused with `[BADEVENT]`
+///
+pub static SUPPORTED_EVENTS: &str = "MessageNew MessageExpunge FlagChange MailboxName SubscriptionChange";
+
+/// Which mailboxes a group of events applies to
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter{
+    /// The selected mailbox, `delayed` holds EXPUNGE back until a command allows it (SELECTED-DELAYED)
+    Selected{ delayed: bool },
+    Inboxes,
+    Personal,
+    Subscribed,
+    Subtree(Vec<String>),
+    Mailboxes(Vec<String>),
+}
+
+/// An event a client can ask to hear about
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event{
+    MessageNew,
+    MessageExpunge,
+    FlagChange,
+    MailboxName,
+    SubscriptionChange,
+}
+
+/// What a client asked for with NOTIFY SET
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notify{
+    /// Send the STATUS of every watched mailbox straight away
+    pub status: bool,
+    pub groups: Vec<(Filter, Vec<Event>)>,
+}
+
+impl Notify{
+    /// Parses the arguments of NOTIFY, [None] for `NONE`. An unknown event gives [Error::BadEvent]
+    ///
+    pub fn parse(args: &[Arg]) -> Result<Option<Self>> {
+        let mut args = args.iter().peekable();
+        match args.next() {
+            Some(arg) if arg.is_atom("NONE") => return Ok(None),
+            Some(arg) if arg.is_atom("SET") => {}
+            _ => return Err(Error::BadArguments("Expected NOTIFY SET or NOTIFY NONE")),
+        }
+        let status = args.next_if(|a| a.is_atom("STATUS")).is_some();
+
+        let mut groups = Vec::new();
+        for group in args {
+            let Arg::List(group) = group else { return Err(Error::BadArguments("Expected an event group")) };
+            let mut parts = group.iter();
+            let filter = match parts.next().and_then(Arg::as_str).map(|f| f.to_uppercase()).as_deref() {
+                Some("SELECTED") => Filter::Selected{ delayed: false },
+                Some("SELECTED-DELAYED") => Filter::Selected{ delayed: true },
+                Some("INBOXES") => Filter::Inboxes,
+                Some("PERSONAL") => Filter::Personal,
+                Some("SUBSCRIBED") => Filter::Subscribed,
+                Some("SUBTREE") => Filter::Subtree(mailbox_names(parts.next())?),
+                Some("MAILBOXES") => Filter::Mailboxes(mailbox_names(parts.next())?),
+                _ => return Err(Error::BadArguments("Unknown mailbox filter")),
+            };
+            let events = match parts.next() {
+                Some(Arg::List(events)) => events.iter().map(parse_event).collect::<Result<Vec<_>>>()?,
+                Some(none) if none.is_atom("NONE") => Vec::new(),
+                _ => return Err(Error::BadArguments("Expected an event list")),
+            };
+            // MessageNew and MessageExpunge go together and FlagChange needs both (RFC 5465 5.1)
+            let has = |event| events.contains(&event);
+            if has(Event::MessageNew) != has(Event::MessageExpunge) || (has(Event::FlagChange) && !has(Event::MessageNew)) {
+                return Err(Error::BadArguments("MessageNew, MessageExpunge and FlagChange must be asked for together"))
+            }
+            groups.push((filter, events));
+        }
+        Ok(Some(Self{ status, groups }))
+    }
+    /// The events asked for the selected mailbox and whether expunges wait for a command
+    ///
+    pub fn selected(&self) -> Option<(&[Event], bool)> {
+        self.groups.iter().find_map(|(filter, events)| match filter {
+            Filter::Selected{ delayed } => Some((events.as_slice(), *delayed)),
+            _ => None,
+        })
+    }
+    /// The events asked for a mailbox other than the selected one, the first group that covers it wins. `subscribed`
+    /// holds the names the user is subscribed to for the SUBSCRIBED filter
+    ///
+    pub fn events_for(&self, name: &str, subscribed: &[String]) -> Option<&[Event]> {
+        let is = |other: &String| other.eq_ignore_ascii_case(name);
+        self.groups.iter().find_map(|(filter, events)| {
+            let covered = match filter {
+                Filter::Selected{ .. } => false,
+                Filter::Inboxes => name.eq_ignore_ascii_case("INBOX"),
+                Filter::Personal => true,
+                Filter::Subscribed => subscribed.iter().any(is),
+                Filter::Subtree(names) => names.iter().any(|n| is(n) || name.to_lowercase().starts_with(&format!("{}/", n.to_lowercase()))),
+                Filter::Mailboxes(names) => names.iter().any(is),
+            };
+            covered.then_some(events.as_slice())
+        })
+    }
+    /// Whether any group asks for an event
+    ///
+    pub fn wants(&self, event: Event) -> bool {
+        self.groups.iter().any(|(_, events)| events.contains(&event))
+    }
+}
+
+/// Reads the mailbox or list of mailboxes after SUBTREE and MAILBOXES
+///
+fn mailbox_names(arg: Option<&Arg>) -> Result<Vec<String>> {
+    match arg {
+        Some(Arg::List(names)) => Ok(names.iter().filter_map(Arg::as_str).map(|n| n.to_owned()).collect()),
+        Some(name) => Ok(name.as_str().into_iter().map(|n| n.to_owned()).collect()),
+        None => Err(Error::BadArguments("Expected a mailbox list")),
+    }
+}
+
+/// Parses one event name
+///
+fn parse_event(arg: &Arg) -> Result<Event> {
+    match arg.as_str().map(|e| e.to_ascii_lowercase()).as_deref() {
+        Some("messagenew") => Ok(Event::MessageNew),
+        Some("messageexpunge") => Ok(Event::MessageExpunge),
+        Some("flagchange") => Ok(Event::FlagChange),
+        Some("mailboxname") => Ok(Event::MailboxName),
+        Some("subscriptionchange") => Ok(Event::SubscriptionChange),
+        _ => Err(Error::BadEvent),
+    }
+}
+
+#[test]
+fn notify_parse_groups(){
+    let args = crate::parser::parse_args("SET STATUS (SELECTED (MessageNew MessageExpunge FlagChange)) \
+        (SUBTREE Lists (MessageNew MessageExpunge)) (PERSONAL (MailboxName))").unwrap();
+    let notify = Notify::parse(&args).unwrap().unwrap();
+    assert!(notify.status);
+    assert_eq!(notify.selected().unwrap().0.len(), 3);
+    assert_eq!(notify.events_for("lists/rust", &[]), Some(&[Event::MessageNew, Event::MessageExpunge][..]));
+    assert_eq!(notify.events_for("Archive", &[]), Some(&[Event::MailboxName][..]));
+
+    let args = crate::parser::parse_args("SET (SUBSCRIBED (MessageNew MessageExpunge FlagChange))").unwrap();
+    let notify = Notify::parse(&args).unwrap().unwrap();
+    assert!(notify.events_for("Archive", &["archive".to_owned()]).is_some());
+    assert_eq!(notify.events_for("Archive", &["INBOX".to_owned()]), None);
+
+    assert_eq!(Notify::parse(&crate::parser::parse_args("NONE").unwrap()).unwrap(), None);
+    assert!(matches!(Notify::parse(&crate::parser::parse_args("SET (INBOXES (Annotate))").unwrap()), Err(Error::BadEvent)));
+    assert!(Notify::parse(&crate::parser::parse_args("SET (INBOXES (MessageNew))").unwrap()).is_err());
+}
@@ -0,0 +1,186 @@
+//! Tokeniser for IMAP command arguments, turns the text after the command name into atoms, strings and lists
+//!
+use crate::error::{Result, Error};
+
+/// How deeply lists may nest, deeper input is refused rather than recursed into
+///
+const MAX_DEPTH: usize = 32;
+
+/// A single argument from a client command
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arg{
+    Atom(String),
+    Str(String),
+    /// A `{n}` literal, kept as bytes since message data need not be UTF-8
+    Literal(Vec<u8>),
+    List(Vec<Arg>),
+}
+
+impl Arg{
+    /// Returns the text of an atom, string or UTF-8 literal, lists have no text
+    ///
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Arg::Atom(s) | Arg::Str(s) => Some(s),
+            Arg::Literal(data) => std::str::from_utf8(data).ok(),
+            Arg::List(_) => None,
+        }
+    }
+    /// Returns the raw bytes of an atom, string or literal
+    ///
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Arg::Atom(s) | Arg::Str(s) => Some(s.as_bytes()),
+            Arg::Literal(data) => Some(data),
+            Arg::List(_) => None,
+        }
+    }
+    /// Case insensitive comparison against an atom, used for keywords like `CATENATE` or `TEXT`
+    ///
+    pub fn is_atom(&self, keyword: &str) -> bool {
+        match self {
+            Arg::Atom(s) => s.eq_ignore_ascii_case(keyword),
+            _ => false,
+        }
+    }
+}
+
+/// Splits a command argument string into [Arg]s, handling quoted strings, `{n}` literals and nested lists
+///
+pub fn parse_args(input: &str) -> Result<Vec<Arg>> {
+    parse_bytes(input.as_bytes())
+}
+
+/// Same as [parse_args] for commands carrying literals that are not necessarily UTF-8, such as APPEND
+///
+pub fn parse_bytes(input: &[u8]) -> Result<Vec<Arg>> {
+    let mut pos = 0;
+    let args = parse_list(input, &mut pos, 0)?;
+    Ok(args)
+}
+
+/// Parses arguments until the end of input or, when `depth` is above 0, the closing bracket of the list
+///
+fn parse_list(bytes: &[u8], pos: &mut usize, depth: usize) -> Result<Vec<Arg>> {
+    if depth > MAX_DEPTH { return Err(Error::BadArguments("Lists nested too deeply")) }
+    let nested = depth > 0;
+    let mut args = Vec::new();
+    loop {
+        while *pos < bytes.len() && bytes[*pos] == b' ' { *pos += 1 }
+        if *pos >= bytes.len() {
+            if nested { return Err(Error::BadArguments("Unterminated list")) }
+            return Ok(args)
+        }
+        match bytes[*pos] {
+            b'(' => {
+                *pos += 1;
+                args.push(Arg::List(parse_list(bytes, pos, depth + 1)?));
+            }
+            b')' => {
+                if !nested { return Err(Error::BadArguments("Unexpected )")) }
+                *pos += 1;
+                return Ok(args)
+            }
+            b'"' => args.push(Arg::Str(parse_quoted(bytes, pos)?)),
+            b'{' => args.push(Arg::Literal(parse_literal(bytes, pos)?)),
+            // literal8 (RFC 3516) carries binary data but is otherwise the same as a literal
+            b'~' if bytes.get(*pos + 1) == Some(&b'{') => {
+                *pos += 1;
+                args.push(Arg::Literal(parse_literal(bytes, pos)?));
+            }
+            _ => args.push(Arg::Atom(parse_atom(bytes, pos))),
+        }
+    }
+}
+
+/// Reads a quoted string, removing the escaping backslashes
+///
+fn parse_quoted(bytes: &[u8], pos: &mut usize) -> Result<String> {
+    let mut res = Vec::new();
+    let mut i = *pos + 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => {
+                res.push(*bytes.get(i + 1).ok_or(Error::BadArguments("Unterminated string"))?);
+                i += 2;
+            }
+            b'"' => {
+                *pos = i + 1;
+                return Ok(String::from_utf8_lossy(&res).to_string())
+            }
+            c => {
+                res.push(c);
+                i += 1;
+            }
+        }
+    }
+    Err(Error::BadArguments("Unterminated string"))
+}
+
+/// Reads a `{n}\r\n` literal (or the `{n+}` LITERAL+ form) followed by exactly n bytes
+///
+fn parse_literal(bytes: &[u8], pos: &mut usize) -> Result<Vec<u8>> {
+    let close = bytes[*pos..].iter().position(|&b| b == b'}').ok_or(Error::BadArguments("Unterminated literal"))? + *pos;
+    let len: usize = String::from_utf8_lossy(&bytes[*pos + 1..close]).trim_end_matches('+').parse()
+        .map_err(|_| Error::BadArguments("Invalid literal length"))?;
+    let start = close + 1;
+    if bytes.get(start..start + 2) != Some(b"\r\n") {
+        return Err(Error::BadArguments("Literal must be followed by CRLF"))
+    }
+    let end = (start + 2).checked_add(len).ok_or(Error::BadArguments("Invalid literal length"))?;
+    let data = bytes.get(start + 2..end)
+        .ok_or(Error::BadArguments("Literal shorter than declared"))?;
+    *pos = end;
+    Ok(data.to_vec())
+}
+
+/// Reads an atom, square brackets may contain spaces and lists such as `BODY[HEADER.FIELDS (From)]`
+///
+fn parse_atom(bytes: &[u8], pos: &mut usize) -> String {
+    let start = *pos;
+    let mut depth = 0;
+    while *pos < bytes.len() {
+        match bytes[*pos] {
+            b'[' => depth += 1,
+            b']' => depth -= 1,
+            b' ' | b'(' | b')' if depth <= 0 => break,
+            _ => {}
+        }
+        *pos += 1;
+    }
+    String::from_utf8_lossy(&bytes[start..*pos]).to_string()
+}
+
+#[test]
+fn parse_args_mixed(){
+    let args = parse_args("INBOX (\\Seen) \"04-Dec-2021 10:00:00 +0000\" {5}\r\nhello").unwrap();
+    assert_eq!(args, vec![
+        Arg::Atom("INBOX".into()),
+        Arg::List(vec![Arg::Atom("\\Seen".into())]),
+        Arg::Str("04-Dec-2021 10:00:00 +0000".into()),
+        Arg::Literal(b"hello".to_vec()),
+    ]);
+    let args = parse_bytes(b"{3}\r\n\xff\x00a").unwrap();
+    assert_eq!(args[0].as_bytes(), Some(&b"\xff\x00a"[..]));
+    assert_eq!(args[0].as_str(), None);
+}
+#[test]
+fn parse_args_literal8(){
+    let args = parse_bytes(b"~{3}\r\n\xff\x00a ~{0+}\r\n").unwrap();
+    assert_eq!(args[0].as_bytes(), Some(&b"\xff\x00a"[..]));
+    assert_eq!(args[1], Arg::Literal(Vec::new()));
+}
+#[test]
+fn parse_args_nesting_limit(){
+    assert!(parse_args(&format!("{}{}", "(".repeat(MAX_DEPTH), ")".repeat(MAX_DEPTH))).is_ok());
+    assert!(parse_args(&"(".repeat(100_000)).is_err());
+}
+#[test]
+fn parse_args_bracketed_atom(){
+    let args = parse_args("1 (UID BODY.PEEK[HEADER.FIELDS (From To)])").unwrap();
+    assert_eq!(args[1], Arg::List(vec![
+        Arg::Atom("UID".into()),
+        Arg::Atom("BODY.PEEK[HEADER.FIELDS (From To)]".into()),
+    ]));
+}
@@ -0,0 +1,350 @@
+//! SEARCH keys (RFC 3501 section 6.4.4), the arguments are parsed into a tree of [SearchKey]s which is then
+//! matched against each message in the mailbox
+//!
+use crate::error::{Result, Error};
+use crate::parser::Arg;
+use crate::sequence::SequenceSet;
+use crate::storage::MessageEntry;
+use crate::email::Email;
+use crate::encoding;
+use chrono::NaiveDate;
+
+/// A single search criterion, a list of keys is held as [SearchKey::And]
+///
+#[derive(Debug)]
+pub enum SearchKey{
+    All,
+    And(Vec<SearchKey>),
+    Not(Box<SearchKey>),
+    Or(Box<SearchKey>, Box<SearchKey>),
+    Sequence(SequenceSet),
+    Uid(SequenceSet),
+    SavedResult,
+    Flag(&'static str),
+    Keyword(String),
+    New,
+    Old,
+    Recent,
+    Before(NaiveDate),
+    On(NaiveDate),
+    Since(NaiveDate),
+    SentBefore(NaiveDate),
+    SentOn(NaiveDate),
+    SentSince(NaiveDate),
+    Larger(usize),
+    Smaller(usize),
+    Header(String, String),
+    Body(String),
+    Text(String),
+    /// `MODSEQ`, messages changed at or after a mod-sequence (RFC 7162)
+    ModSeq(u64),
+}
+
+/// What a [SearchKey] is matched against, the parsed message is only loaded when a key needs it
+///
+pub struct Candidate<'a>{
+    pub seq: u32,
+    pub entry: &'a MessageEntry,
+    pub email: Option<&'a Email>,
+    pub largest_seq: u32,
+    pub largest_uid: u32,
+    pub saved: &'a [u32],
+    /// Whether the message is \Recent to this session, as the RECENT count it was sent says
+    pub recent: bool,
+}
+
+/// What a SEARCH found: the sequence numbers or UIDs matched, the `RETURN (...)` options asking for ESEARCH and, when
+/// the keys used MODSEQ, the highest MODSEQ of the matches
+///
+#[derive(Debug)]
+pub struct SearchResult{
+    pub matches: Vec<u32>,
+    pub returns: Option<Vec<ReturnOption>>,
+    pub highest_modseq: Option<u64>,
+}
+
+/// The result options of `SEARCH RETURN (...)` from RFC 4731 and RFC 5182
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReturnOption{
+    Min,
+    Max,
+    Count,
+    All,
+    Save,
+}
+
+/// Removes a leading `RETURN (...)` from the search arguments. [None] means the client wants a plain SEARCH response,
+/// an empty `RETURN ()` is the same as `RETURN (ALL)`
+///
+pub fn take_return_options(args: &mut Vec<Arg>) -> Result<Option<Vec<ReturnOption>>> {
+    if !args.first().is_some_and(|a| a.is_atom("RETURN")) { return Ok(None) }
+    let options = match args.get(1) {
+        Some(Arg::List(list)) => list,
+        _ => return Err(Error::BadArguments("RETURN must be followed by a list")),
+    };
+    let mut returns = Vec::new();
+    for option in options {
+        returns.push(match option.as_str().map(|o| o.to_uppercase()).as_deref() {
+            Some("MIN") => ReturnOption::Min,
+            Some("MAX") => ReturnOption::Max,
+            Some("COUNT") => ReturnOption::Count,
+            Some("ALL") => ReturnOption::All,
+            Some("SAVE") => ReturnOption::Save,
+            _ => return Err(Error::BadArguments("Unknown RETURN option")),
+        });
+    }
+    if returns.is_empty() { returns.push(ReturnOption::All) }
+    args.drain(..2);
+    Ok(Some(returns))
+}
+
+/// Which results SAVE keeps, when only MIN and/or MAX are asked for alongside it just those are saved (RFC 5182)
+///
+pub fn saved_results(results: &[u32], returns: &[ReturnOption]) -> Vec<u32> {
+    let only_min_max = !returns.contains(&ReturnOption::All) && !returns.contains(&ReturnOption::Count)
+        && (returns.contains(&ReturnOption::Min) || returns.contains(&ReturnOption::Max));
+    if !only_min_max { return results.to_vec() }
+    let mut saved = Vec::new();
+    if returns.contains(&ReturnOption::Min) { saved.extend(results.iter().min()) }
+    if returns.contains(&ReturnOption::Max) { saved.extend(results.iter().max()) }
+    saved.dedup();
+    saved
+}
+
+/// Formats an ESEARCH response (RFC 4731), MIN, MAX and ALL are left out when nothing matched. `modseq` is the
+/// highest MODSEQ of the matches, sent when the search used the MODSEQ key (RFC 7162)
+///
+pub fn format_esearch(tag: &str, uid: bool, results: &[u32], returns: &[ReturnOption], modseq: Option<u64>) -> String {
+    let mut line = format!("ESEARCH (TAG \"{}\")", tag);
+    if uid { line.push_str(" UID") }
+    for option in returns {
+        match option {
+            ReturnOption::Min => if let Some(min) = results.iter().min() { line.push_str(&format!(" MIN {}", min)) },
+            ReturnOption::Max => if let Some(max) = results.iter().max() { line.push_str(&format!(" MAX {}", max)) },
+            ReturnOption::Count => line.push_str(&format!(" COUNT {}", results.len())),
+            ReturnOption::All => if !results.is_empty() {
+                line.push_str(&format!(" ALL {}", SequenceSet::from_numbers(results)))
+            },
+            ReturnOption::Save => {}
+        }
+    }
+    if let Some(modseq) = modseq.filter(|_| !results.is_empty()) {
+        line.push_str(&format!(" MODSEQ {}", modseq));
+    }
+    line.push_str("\r\n");
+    line
+}
+
+impl SearchKey{
+    /// Parses a full search program, the keys are ANDed together
+    ///
+    pub fn parse(args: &[Arg]) -> Result<Self> {
+        let mut args = args.iter();
+        let mut keys = Vec::new();
+        while let Some(arg) = args.next() {
+            keys.push(Self::parse_key(arg, &mut args)?);
+        }
+        match keys.len() {
+            0 => Err(Error::BadArguments("Missing search criteria")),
+            1 => Ok(keys.remove(0)),
+            _ => Ok(SearchKey::And(keys)),
+        }
+    }
+    /// Parses one key starting at `arg`, taking any values it needs from `rest`
+    ///
+    fn parse_key<'a>(arg: &'a Arg, rest: &mut impl Iterator<Item = &'a Arg>) -> Result<Self> {
+        let atom = match arg {
+            Arg::List(list) => return Self::parse(list),
+            Arg::Str(_) | Arg::Literal(_) => return Err(Error::BadArguments("Expected search key")),
+            Arg::Atom(atom) => atom.to_uppercase(),
+        };
+        let mut value = || rest.next().and_then(Arg::as_str).map(|s| s.to_owned())
+            .ok_or(Error::BadArguments("Search key missing value"));
+
+        Ok(match atom.as_str() {
+            "ALL" => SearchKey::All,
+            "ANSWERED" => SearchKey::Flag("\\Answered"),
+            "DELETED" => SearchKey::Flag("\\Deleted"),
+            "DRAFT" => SearchKey::Flag("\\Draft"),
+            "FLAGGED" => SearchKey::Flag("\\Flagged"),
+            "SEEN" => SearchKey::Flag("\\Seen"),
+            "UNANSWERED" => SearchKey::Not(Box::new(SearchKey::Flag("\\Answered"))),
+            "UNDELETED" => SearchKey::Not(Box::new(SearchKey::Flag("\\Deleted"))),
+            "UNDRAFT" => SearchKey::Not(Box::new(SearchKey::Flag("\\Draft"))),
+            "UNFLAGGED" => SearchKey::Not(Box::new(SearchKey::Flag("\\Flagged"))),
+            "UNSEEN" => SearchKey::Not(Box::new(SearchKey::Flag("\\Seen"))),
+            "KEYWORD" => SearchKey::Keyword(value()?),
+            "UNKEYWORD" => SearchKey::Not(Box::new(SearchKey::Keyword(value()?))),
+            "NEW" => SearchKey::New,
+            "OLD" => SearchKey::Old,
+            "RECENT" => SearchKey::Recent,
+            "BEFORE" => SearchKey::Before(parse_date(&value()?)?),
+            "ON" => SearchKey::On(parse_date(&value()?)?),
+            "SINCE" => SearchKey::Since(parse_date(&value()?)?),
+            "SENTBEFORE" => SearchKey::SentBefore(parse_date(&value()?)?),
+            "SENTON" => SearchKey::SentOn(parse_date(&value()?)?),
+            "SENTSINCE" => SearchKey::SentSince(parse_date(&value()?)?),
+            "LARGER" => SearchKey::Larger(value()?.parse().map_err(|_| Error::BadArguments("Invalid size"))?),
+            "SMALLER" => SearchKey::Smaller(value()?.parse().map_err(|_| Error::BadArguments("Invalid size"))?),
+            "BCC" | "CC" | "FROM" | "SUBJECT" | "TO" => SearchKey::Header(atom, value()?),
+            "HEADER" => {
+                let field = value()?;
+                SearchKey::Header(field, value()?)
+            }
+            "BODY" => SearchKey::Body(value()?),
+            "TEXT" => SearchKey::Text(value()?),
+            "UID" => match value()?.as_str() {
+                "$" => SearchKey::SavedResult,
+                set => SearchKey::Uid(SequenceSet::try_from(set)?),
+            },
+            "$" => SearchKey::SavedResult,
+            "MODSEQ" => {
+                // The optional entry name and type only matter to servers keeping a mod-sequence per flag
+                let mut modseq = value()?;
+                if modseq.starts_with('/') {
+                    value()?;
+                    modseq = value()?;
+                }
+                SearchKey::ModSeq(modseq.parse().map_err(|_| Error::BadArguments("Invalid mod-sequence"))?)
+            }
+            "NOT" => {
+                let next = rest.next().ok_or(Error::BadArguments("NOT missing key"))?;
+                SearchKey::Not(Box::new(Self::parse_key(next, rest)?))
+            }
+            "OR" => {
+                let first = rest.next().ok_or(Error::BadArguments("OR missing key"))?;
+                let first = Self::parse_key(first, rest)?;
+                let second = rest.next().ok_or(Error::BadArguments("OR missing key"))?;
+                SearchKey::Or(Box::new(first), Box::new(Self::parse_key(second, rest)?))
+            }
+            _ => SearchKey::Sequence(SequenceSet::try_from(atom.as_str())?),
+        })
+    }
+    /// True if matching needs the message contents rather than just the index entry
+    ///
+    pub fn needs_email(&self) -> bool {
+        match self {
+            SearchKey::And(keys) => keys.iter().any(|k| k.needs_email()),
+            SearchKey::Not(key) => key.needs_email(),
+            SearchKey::Or(a, b) => a.needs_email() || b.needs_email(),
+            SearchKey::SentBefore(_) | SearchKey::SentOn(_) | SearchKey::SentSince(_) |
+            SearchKey::Header(_, _) | SearchKey::Body(_) | SearchKey::Text(_) => true,
+            _ => false,
+        }
+    }
+    /// True if the key contains MODSEQ, the response then carries the highest MODSEQ of the matches
+    ///
+    pub fn uses_modseq(&self) -> bool {
+        match self {
+            SearchKey::And(keys) => keys.iter().any(|k| k.uses_modseq()),
+            SearchKey::Not(key) => key.uses_modseq(),
+            SearchKey::Or(a, b) => a.uses_modseq() || b.uses_modseq(),
+            SearchKey::ModSeq(_) => true,
+            _ => false,
+        }
+    }
+    /// Checks a message against the key
+    ///
+    pub fn matches(&self, message: &Candidate) -> bool {
+        let has_flag = |flag: &str| message.entry.flags.iter().any(|f| f.eq_ignore_ascii_case(flag));
+        // The date in the zone the message was stamped with, RFC 3501 disregards time and timezone
+        let internal_date = || message.entry.internal_date.naive_local().date();
+        match self {
+            SearchKey::All => true,
+            SearchKey::And(keys) => keys.iter().all(|k| k.matches(message)),
+            SearchKey::Not(key) => !key.matches(message),
+            SearchKey::Or(a, b) => a.matches(message) || b.matches(message),
+            SearchKey::Sequence(set) => set.contains(message.seq, message.largest_seq),
+            SearchKey::Uid(set) => set.contains(message.entry.uid, message.largest_uid),
+            SearchKey::SavedResult => message.saved.contains(&message.entry.uid),
+            SearchKey::Flag(flag) => has_flag(flag),
+            SearchKey::Keyword(keyword) => has_flag(keyword),
+            SearchKey::New => message.recent && !has_flag("\\Seen"),
+            SearchKey::Recent => message.recent,
+            SearchKey::Old => !message.recent,
+            SearchKey::Before(date) => internal_date() < *date,
+            SearchKey::On(date) => internal_date() == *date,
+            SearchKey::Since(date) => internal_date() >= *date,
+            SearchKey::SentBefore(date) => sent_date(message).is_some_and(|d| d < *date),
+            SearchKey::SentOn(date) => sent_date(message).is_some_and(|d| d == *date),
+            SearchKey::SentSince(date) => sent_date(message).is_some_and(|d| d >= *date),
+            SearchKey::Larger(size) => message.entry.size > *size,
+            SearchKey::Smaller(size) => message.entry.size < *size,
+            SearchKey::Header(field, value) => message.email.is_some_and(|e| {
+                e.headers().get_all_decoded(field).iter().any(|h| contains_text(h, value))
+            }),
+            SearchKey::Body(value) => message.email.is_some_and(|e| contains_text(&e.decoded_body(), value)),
+            SearchKey::Text(value) => message.email.is_some_and(|e| {
+                let header = e.section("HEADER").map(|h| encoding::decode_encoded_words(&String::from_utf8_lossy(&h))).unwrap_or_default();
+                contains_text(&header, value) || contains_text(&e.decoded_body(), value)
+            }),
+            SearchKey::ModSeq(modseq) => message.entry.modseq >= *modseq,
+        }
+    }
+}
+
+/// The date from the Date header, the time and timezone are disregarded as RFC 3501 requires
+///
+fn sent_date(message: &Candidate) -> Option<NaiveDate> {
+    message.email?.headers().date().map(|d| d.naive_local().date())
+}
+
+/// Parses a search date in `dd-Mon-yyyy` form
+///
+fn parse_date(date: &str) -> Result<NaiveDate> {
+    NaiveDate::parse_from_str(date, "%d-%b-%Y").map_err(|_| Error::InvalidDate)
+}
+
+/// Substring match on case folded text used by the text keys
+///
+fn contains_text(haystack: &str, needle: &str) -> bool {
+    encoding::fold_case(haystack).contains(&encoding::fold_case(needle))
+}
+
+#[test]
+fn search_parse_nested(){
+    let args = crate::parser::parse_args("OR (FROM adam SINCE 01-Nov-2021) DRAFT NOT SEEN 1:*").unwrap();
+    let key = SearchKey::parse(&args).unwrap();
+    assert!(key.needs_email());
+    match key {
+        SearchKey::And(keys) => {
+            assert!(matches!(keys[0], SearchKey::Or(_, _)));
+            assert!(matches!(keys[1], SearchKey::Not(_)));
+            assert!(matches!(keys[2], SearchKey::Sequence(_)));
+        }
+        _ => panic!("Expected AND of keys"),
+    }
+}
+#[test]
+fn search_match_headers(){
+    let email = Email::new("1", "1", "test_emails/DisplayNames.eml").unwrap();
+    let entry = MessageEntry{ uid: 7, internal_date: crate::storage::parse_date_time("23-Nov-2021 16:56:32 +0000").unwrap(), flags: vec!["\\Seen".into()], file: String::new(), size: 100, modseq: 5 };
+    let message = Candidate{ seq: 1, entry: &entry, email: Some(&email), largest_seq: 1, largest_uid: 7, saved: &[7], recent: false };
+    let search = |s: &str| SearchKey::parse(&crate::parser::parse_args(s).unwrap()).unwrap().matches(&message);
+
+    assert!(search("FROM \"adam the rusty\" SEEN"));
+    assert!(search("SENTON 23-Nov-2021 ON 23-Nov-2021 UID 5:*"));
+    assert!(!search("OR UNSEEN SUBJECT holiday"));
+    assert!(search("HEADER X-Secret-Header secret BODY builder"));
+    assert!(search("NOT LARGER 100 SMALLER 101"));
+    assert!(search("$ UID $"));
+    assert!(search("BODY 안녕하세요 TEXT \"ADAM THE RUSTY\""));
+    assert!(search("MODSEQ 5 NOT MODSEQ \"/flags/\\\\draft\" all 6"));
+    assert!(search("OLD NOT RECENT NOT NEW"));
+
+    let recent = Candidate{ recent: true, ..message };
+    let search = |s: &str| SearchKey::parse(&crate::parser::parse_args(s).unwrap()).unwrap().matches(&recent);
+    assert!(search("RECENT NOT OLD NOT NEW"));
+}
+#[test]
+fn search_return_options(){
+    let mut args = crate::parser::parse_args("RETURN (MIN COUNT SAVE) SEEN").unwrap();
+    let returns = take_return_options(&mut args).unwrap().unwrap();
+    assert_eq!(returns, vec![ReturnOption::Min, ReturnOption::Count, ReturnOption::Save]);
+    assert_eq!(args.len(), 1);
+    assert_eq!(format_esearch("A1", true, &[4, 2, 3, 9], &returns, None), "ESEARCH (TAG \"A1\") UID MIN 2 COUNT 4\r\n");
+    assert_eq!(format_esearch("A2", false, &[], &[ReturnOption::All, ReturnOption::Count], Some(3)), "ESEARCH (TAG \"A2\") COUNT 0\r\n");
+    assert_eq!(format_esearch("A3", false, &[1, 2], &[ReturnOption::All], Some(7)), "ESEARCH (TAG \"A3\") ALL 1:2 MODSEQ 7\r\n");
+    assert_eq!(saved_results(&[4, 2, 9], &[ReturnOption::Max, ReturnOption::Save]), vec![9]);
+}
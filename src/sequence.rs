@@ -0,0 +1,139 @@
+//! Sequence sets such as `1,3:7,10:*` used to address messages by sequence number or UID
+//!
+use crate::error::{Result, Error};
+
+/// One end of a range, `*` stands for the largest number in use in the mailbox
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SeqNumber{
+    Number(u32),
+    Star,
+}
+
+impl SeqNumber{
+    /// Resolves `*` against the largest sequence number or UID in the mailbox
+    ///
+    fn resolve(&self, largest: u32) -> u32 {
+        match self {
+            SeqNumber::Number(n) => *n,
+            SeqNumber::Star => largest,
+        }
+    }
+}
+
+/// A parsed sequence set, single numbers are stored as a range of one
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct SequenceSet{
+    ranges: Vec<(SeqNumber, SeqNumber)>,
+}
+
+impl TryFrom<&str> for SequenceSet{
+    type Error = Error;
+
+    fn try_from(s: &str) -> Result<Self> {
+        let number = |n: &str| -> Result<SeqNumber> {
+            match n {
+                "*" => Ok(SeqNumber::Star),
+                _ => match n.parse() {
+                    Ok(0) | Err(_) => Err(Error::BadArguments("Invalid sequence set")),
+                    Ok(n) => Ok(SeqNumber::Number(n)),
+                },
+            }
+        };
+        let mut ranges = Vec::new();
+        for part in s.trim().split(',') {
+            let range = match part.split_once(':') {
+                // `5:3` means the same as `3:5`, ranges involving `*` can only be ordered once it is resolved
+                Some((from, to)) => match (number(from)?, number(to)?) {
+                    (SeqNumber::Number(from), SeqNumber::Number(to)) if from > to => (SeqNumber::Number(to), SeqNumber::Number(from)),
+                    range => range,
+                },
+                None => (number(part)?, number(part)?),
+            };
+            ranges.push(range);
+        }
+        Ok(Self{ ranges })
+    }
+}
+
+impl SequenceSet{
+    /// Builds the most compact set covering the given numbers, e.g. `[1, 2, 3, 5]` becomes `1:3,5`
+    ///
+    pub fn from_numbers(numbers: &[u32]) -> Self {
+        let mut sorted = numbers.to_vec();
+        sorted.sort_unstable();
+        sorted.dedup();
+        let mut ranges: Vec<(SeqNumber, SeqNumber)> = Vec::new();
+        for n in sorted {
+            match ranges.last_mut() {
+                Some((_, SeqNumber::Number(end))) if *end + 1 == n => *end = n,
+                _ => ranges.push((SeqNumber::Number(n), SeqNumber::Number(n))),
+            }
+        }
+        Self{ ranges }
+    }
+    /// Checks if a number is in the set, `largest` is what `*` means in the current mailbox
+    ///
+    pub fn contains(&self, n: u32, largest: u32) -> bool {
+        self.ranges.iter().any(|(from, to)| {
+            let (from, to) = (from.resolve(largest), to.resolve(largest));
+            from.min(to) <= n && n <= from.max(to)
+        })
+    }
+    /// Checks every number in the set, `*` included, is between 1 and `largest`. Sequence numbers past the end of the
+    /// mailbox are an error where UIDs that do not exist are simply ignored. In an empty mailbox a range with `*`,
+    /// such as `1:*`, just covers nothing
+    ///
+    pub fn within(&self, largest: u32) -> bool {
+        self.ranges.iter().all(|(from, to)| {
+            if largest == 0 && (*from == SeqNumber::Star || *to == SeqNumber::Star) { return true }
+            let (from, to) = (from.resolve(largest), to.resolve(largest));
+            from >= 1 && to >= 1 && from <= largest && to <= largest
+        })
+    }
+    /// True when the set holds no numbers at all
+    ///
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+impl std::fmt::Display for SequenceSet{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        let number = |n: &SeqNumber| match n {
+            SeqNumber::Number(n) => n.to_string(),
+            SeqNumber::Star => "*".to_owned(),
+        };
+        let parts: Vec<String> = self.ranges.iter().map(|(from, to)| {
+            if from == to { number(from) } else { format!("{}:{}", number(from), number(to)) }
+        }).collect();
+        write!(f, "{}", parts.join(","))
+    }
+}
+
+#[test]
+fn sequence_set_contains(){
+    let set = SequenceSet::try_from("1,3:4,9:*").unwrap();
+    assert!(set.contains(1, 12));
+    assert!(!set.contains(2, 12));
+    assert!(set.contains(4, 12));
+    assert!(set.contains(12, 12));
+    assert!(SequenceSet::try_from("0").is_err());
+
+    let set = SequenceSet::try_from("7:3,10:*").unwrap();
+    assert_eq!(set.to_string(), "3:7,10:*");
+    assert!(set.contains(5, 8) && set.contains(9, 8) && !set.contains(2, 8));
+    assert!(set.within(12) && !set.within(6) && !SequenceSet::try_from("1").unwrap().within(0));
+}
+#[test]
+fn sequence_set_empty_mailbox(){
+    let set = SequenceSet::try_from("1:*").unwrap();
+    assert!(set.within(0));
+    assert!(SequenceSet::try_from("*").unwrap().within(0));
+    assert!(!SequenceSet::try_from("1:*,2").unwrap().within(0));
+}
+#[test]
+fn sequence_set_compact(){
+    assert_eq!(SequenceSet::from_numbers(&[5, 1, 2, 3, 7, 8]).to_string(), "1:3,5,7:8");
+}
@@ -1,14 +1,50 @@
 use crate::error::{Result, Error};
-use crate::email::Email;
-use chrono::prelude::{Utc, TimeZone};
-use std::fs;
-
-static MAIL_ROOT: &'static str = "D:/MAILSERVER";
+use std::borrow::Cow;
+use crate::email::{self, Email};
+use crate::storage::{self, Mailbox, MessageEntry, StoreMode};
+use crate::changes::{self, Change};
+use crate::notify::{Notify, Event};
+use crate::parser::{self, Arg};
+use crate::imapurl::ImapUrl;
+use crate::sequence::SequenceSet;
+use crate::fetch::{self, FetchAttr};
+use crate::stream::Chunk;
+use crate::types::Extension;
+use crate::encoding;
+use crate::search::{self, SearchKey, Candidate, ReturnOption, SearchResult};
+use chrono::prelude::Utc;
+use std::path::PathBuf;
 
 #[derive(Debug, Default)]
 pub struct UserSession{
     email: Option<String>,
     username: Option<String>,
+    selected: Option<String>,
+    /// Whether the selected mailbox was opened with EXAMINE, nothing about it may then change
+    read_only: bool,
+    saved_search: Vec<u32>,
+    /// UIDs of the selected mailbox in the sequence number order this client was last told about
+    view: Vec<u32>,
+    /// How far through the selected mailbox's change log this client has been told
+    position: u64,
+    /// Messages expunged by others that stay in the view until an EXPUNGE response is allowed
+    deferred: Vec<u32>,
+    /// Messages that arrived since SELECT, counted by RECENT
+    recent: Vec<u32>,
+    /// Flag changes this client already saw in its own STORE or FETCH responses, each skipped once in the change log
+    own_flags: Vec<u32>,
+    /// What the client asked to hear about with NOTIFY
+    notify: Option<Notify>,
+    /// Mailboxes NOTIFY covers with their folders and how far through each change log the client has been told
+    watched: Vec<(String, PathBuf, u64)>,
+    /// Folders the client was last told about, for MailboxName events
+    folders: Vec<String>,
+    /// Subscriptions the client was last told about and [changes::subscriptions_version] then, for SubscriptionChange
+    subscriptions: (Vec<String>, u64),
+    /// Extensions switched on with ENABLE, CONDSTORE is also turned on by the first command that uses it (RFC 7162 3.1)
+    enabled: Vec<Extension>,
+    /// Most of a message that may be read into memory at once, `session_memory_limit` in config.aml
+    memory_limit: usize,
     pub mail_count: usize,
     pub authenticated: bool,
     pub bad_attempts: u8,
@@ -19,15 +55,41 @@ pub struct UserSession{
 impl UserSession{
 
     pub fn new() -> Self{
-        Self::default()
+        Self{ memory_limit: email::MAX_BUFFERED, ..Self::default() }
+    }
+    /// Sets how much of a message the session may hold in memory at once, larger messages are parsed and served
+    /// from their files
+    ///
+    pub fn with_memory_limit(mut self, limit: usize) -> Self {
+        self.memory_limit = limit;
+        self
     }
-    /// Authenticates
+    /// Authenticates, a LOGIN without a user name leaves the session logged out
     pub fn authenticate(&mut self, creds: &str) {
         let tmp = creds.replace(&['\"', '\r','\n'][..], "");
         let mut sc = tmp.rsplit(" ");   
-        let pass = sc.next().unwrap_or("");
-        let user = sc.next().unwrap_or("");
-        println!("User: {}, Password: {}", user, pass);
+        // The password comes last, it is not printed
+        let user = sc.nth(1).unwrap_or("");
+        if user.is_empty() { return }
+        println!("User: {}", user);
+        self.log_in(user);
+    }
+    /// Authenticates with the SASL PLAIN response of AUTHENTICATE, `authzid NUL authcid NUL passwd` in base64 (RFC 4616).
+    /// Returns false when the response cannot be read or asks to act as a different user than the one logging in
+    ///
+    pub fn authenticate_plain(&mut self, response: &[u8]) -> bool {
+        let Some(decoded) = encoding::decode_base64(response) else { return false };
+        let mut parts = decoded.split(|&b| b == 0);
+        let (Some(authzid), Some(user), Some(_), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else { return false };
+        if user.is_empty() || (!authzid.is_empty() && authzid != user) { return false }
+        let user = String::from_utf8_lossy(user);
+        println!("User: {}", user);
+        self.log_in(&user);
+        true
+    }
+    /// Marks the session as logged in as `user`
+    ///
+    fn log_in(&mut self, user: &str) {
         self.email = Some(user.to_string());
         self.username = Some(user.split("@").next().unwrap().to_string());
         self.authenticated = true;
@@ -35,258 +97,1096 @@ impl UserSession{
     /// Counts number of emails in Inbox
     /// 
     pub fn count_emails(&mut self) -> Result<()>{
+        self.mail_count = self.inbox()?.messages.len();
+        Ok(())
+    }
+    /// Opens the users Inbox from storage
+    ///
+    pub fn inbox(&self) -> Result<Mailbox>{
         let user =  self.username.as_ref().ok_or(Error::FolderLookup("Username Invalid"))?;
-        let path_str = &format!("{}/mail/{}/Inbox", MAIL_ROOT ,user);
-        let path = std::path::Path::new(path_str);
-        self.mail_count = std::fs::read_dir(path).map_err(Error::IO)?.count();
+        Mailbox::open(user, "INBOX")
+    }
+    /// Appends a message to a mailbox, the message is either a literal or built with CATENATE (RFC 4469) from
+    /// TEXT parts and URLs pointing at sections of messages the user already has. Returns UIDVALIDITY and the new UID
+    ///
+    pub fn append(&self, msg: &[u8]) -> Result<(u32, u32)>{
+        let user = self.username.as_ref().ok_or(Error::FolderLookup("Username Invalid"))?;
+        let args = parser::parse_bytes(msg)?;
+        let mut args = args.iter();
+
+        let mailbox_name = args.next().and_then(Arg::as_str).ok_or(Error::BadArguments("Missing mailbox"))?;
+        let mut rest: Vec<&Arg> = args.collect();
+
+        let mut flags = Vec::new();
+        if let Some(Arg::List(list)) = rest.first() {
+            flags = list.iter().filter_map(Arg::as_str).map(|f| f.to_owned()).collect();
+            rest.remove(0);
+        }
+        let mut internal_date = Utc::now().into();
+        if rest.len() > 1 {
+            if let Arg::Str(date) = rest[0] {
+                internal_date = storage::parse_date_time(date)?;
+                rest.remove(0);
+            }
+        }
+        let message = match rest.as_slice() {
+            [Arg::Literal(literal)] => literal.to_owned(),
+            [catenate, Arg::List(parts)] if catenate.is_atom("CATENATE") => self.catenate(user, parts)?,
+            _ => return Err(Error::BadArguments("Expected message literal or CATENATE")),
+        };
+
+        let mut mailbox = Mailbox::open(user, mailbox_name)?;
+        let uid = mailbox.append(&message, flags, internal_date)?;
+        Ok((mailbox.uid_validity, uid))
+    }
+    /// Builds a message from CATENATE parts, `TEXT` parts are used as is and `URL` parts are resolved to the section
+    /// of the referenced message using the same section extraction as `BODY[section]` FETCH
+    ///
+    fn catenate(&self, user: &str, parts: &[Arg]) -> Result<Vec<u8>>{
+        let mut message = Vec::new();
+        let mut parts = parts.iter();
+        while let Some(kind) = parts.next() {
+            let value = parts.next().ok_or(Error::BadArguments("CATENATE part missing value"))?;
+            if kind.is_atom("TEXT") {
+                message.extend_from_slice(value.as_bytes().ok_or(Error::BadArguments("CATENATE TEXT must be a literal"))?);
+            } else if kind.is_atom("URL") {
+                let url = value.as_str().ok_or(Error::BadArguments("CATENATE URL must be a string"))?;
+                message.extend_from_slice(&self.resolve_url(user, url)?);
+            } else {
+                return Err(Error::BadArguments("CATENATE part must be TEXT or URL"))
+            }
+        }
+        Ok(message)
+    }
+    /// Fetches the text an IMAP URL points at, any failure is reported as [Error::BadUrl] so the client gets `[BADURL]`
+    ///
+    fn resolve_url(&self, user: &str, url: &str) -> Result<Vec<u8>>{
+        let bad = || Error::BadUrl(url.to_owned());
+        let parsed = ImapUrl::try_from(url)?;
+        if let Some(url_user) = &parsed.user {
+            if url_user.split('@').next() != Some(user) { return Err(bad()) }
+        }
+        let mailbox = Mailbox::open(user, &parsed.mailbox).map_err(|_| bad())?;
+        if parsed.uid_validity.is_some_and(|v| v != mailbox.uid_validity) { return Err(bad()) }
+        let entry = mailbox.by_uid(parsed.uid).ok_or_else(bad)?;
+
+        let email = Email::new(&entry.uid.to_string(), "0", mailbox.message_path(entry)).map_err(|_| bad())?;
+        let section = email.section(&parsed.section).map_err(|_| bad())?;
+        let section = match parsed.partial {
+            Some((offset, length)) => {
+                let start = offset.min(section.len());
+                let end = length.map_or(section.len(), |l| (start + l).min(section.len()));
+                &section[start..end]
+            }
+            None => &section[..],
+        };
+        Ok(section.to_vec())
+    }
+    /// Turns on the extensions ENABLE names that this server has (RFC 5161), returning those that were not on
+    /// already. Names it does not know are ignored, and ENABLE has to come before SELECT
+    ///
+    pub fn enable(&mut self, msg: &str) -> Result<Vec<Extension>>{
+        if self.selected.is_some() { return Err(Error::BadArguments("ENABLE must come before SELECT")) }
+        let mut enabled = Vec::new();
+        for extension in parser::parse_args(msg)?.iter().filter_map(Arg::as_str).filter_map(Extension::from_name) {
+            if self.turn_on(extension) { enabled.push(extension) }
+        }
+        Ok(enabled)
+    }
+    /// Whether an extension is on for this connection
+    ///
+    pub fn is_enabled(&self, extension: Extension) -> bool {
+        self.enabled.contains(&extension)
+    }
+    /// Switches an extension on, QRESYNC brings CONDSTORE with it. Returns false if it already was on
+    ///
+    fn turn_on(&mut self, extension: Extension) -> bool {
+        if extension == Extension::QResync { self.turn_on(Extension::CondStore); }
+        if self.is_enabled(extension) { return false }
+        self.enabled.push(extension);
+        true
+    }
+    /// A folder name as this client expects it, modified UTF-7 unless UTF8=ACCEPT or IMAP4rev2 is on (RFC 6855 3)
+    ///
+    fn mailbox_name<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        match utf8_names(&self.enabled) {
+            true => Cow::Borrowed(name),
+            false => encoding::encode_mutf7(name),
+        }
+    }
+    /// A mailbox name from the client as the folder is called on disk, or decoded as this client encodes names when
+    /// there is no such folder
+    ///
+    fn folder_name(&self, user: &str, name: &str) -> String {
+        if let Some(folder) = storage::mailbox_path(user, name).as_ref().and_then(|p| p.file_name()) {
+            return folder.to_string_lossy().into_owned()
+        }
+        match utf8_names(&self.enabled) {
+            true => name.to_owned(),
+            false => encoding::decode_mutf7(name).unwrap_or_else(|| name.to_owned()),
+        }
+    }
+    /// Whether SELECT now closes a mailbox with QRESYNC on, the client is told with `[CLOSED]` (RFC 7162 3.2.11)
+    ///
+    pub fn closes_selected(&self) -> bool {
+        self.is_enabled(Extension::QResync) && self.selected.is_some()
+    }
+    /// Selects a mailbox, later commands that work on messages use it. A `(CONDSTORE)` parameter after the name turns
+    /// on CONDSTORE and `(QRESYNC (uidvalidity modseq [known-uids]))` returns the VANISHED (EARLIER) and FETCH
+    /// responses that bring the client's copy up to date
+    ///
+    pub fn select(&mut self, msg: &str) -> Result<(Mailbox, Vec<String>)>{
+        let user =  self.username.clone().ok_or(Error::FolderLookup("Username Invalid"))?;
+        let args = parser::parse_args(msg)?;
+        let (name, params) = match args.as_slice() {
+            [name] => (name, &[][..]),
+            [name, Arg::List(params)] => (name, params.as_slice()),
+            _ => return Err(Error::BadArguments("Expected mailbox name")),
+        };
+        let name = name.as_str().ok_or(Error::BadArguments("Expected mailbox name"))?;
+        let mut resync = None;
+        let mut params = params.iter();
+        while let Some(param) = params.next() {
+            if param.is_atom("CONDSTORE") {
+                self.turn_on(Extension::CondStore);
+            } else if param.is_atom("QRESYNC") {
+                if !self.is_enabled(Extension::QResync) { return Err(Error::BadArguments("QRESYNC must be enabled first")) }
+                let Some(Arg::List(values)) = params.next() else { return Err(Error::BadArguments("Expected QRESYNC parameters")) };
+                resync = Some(parse_qresync(values)?);
+            } else {
+                return Err(Error::BadArguments("Unknown SELECT parameter"))
+            }
+        }
+        let mailbox = Mailbox::open(&user, name)?;
+        self.selected = Some(name.to_owned());
+        self.read_only = false;
+        self.saved_search.clear();
+        self.view = mailbox.messages.iter().map(|m| m.uid).collect();
+        self.position = mailbox.change_position();
+        self.deferred.clear();
+        self.recent.clear();
+        self.own_flags.clear();
+        self.mail_count = mailbox.messages.len();
+
+        // A client whose UIDVALIDITY is out of date has to start again so gets nothing extra
+        let mut responses = Vec::new();
+        if let Some((_, modseq, known)) = resync.filter(|(validity, _, _)| *validity == mailbox.uid_validity) {
+            let largest = mailbox.uid_next.saturating_sub(1);
+            let vanished = vanished_since(&mailbox, modseq, |u| known.as_ref().is_none_or(|k| k.contains(u, largest)));
+            if !vanished.is_empty() {
+                responses.push(format!("VANISHED (EARLIER) {}", SequenceSet::from_numbers(&vanished)));
+            }
+            for (index, entry) in mailbox.messages.iter().enumerate().filter(|(_, m)| m.modseq > modseq) {
+                responses.push(format!("{} FETCH ({})", index + 1, self.flag_items(entry, true)));
+            }
+        }
+        Ok((mailbox, responses))
+    }
+    /// EXAMINE, selects a mailbox as [UserSession::select] does but read-only so EXPUNGE, STORE and MOVE are refused
+    /// and fetching a body leaves `\Seen` alone
+    ///
+    pub fn examine(&mut self, msg: &str) -> Result<(Mailbox, Vec<String>)>{
+        let selected = self.select(msg)?;
+        self.read_only = true;
+        Ok(selected)
+    }
+    /// UNSELECT, leaves the selected mailbox without expunging anything. Errors when none is selected
+    ///
+    pub fn unselect(&mut self) -> Result<()>{
+        if self.selected.take().is_none() { return Err(Error::BadArguments("No mailbox selected")) }
+        self.read_only = false;
+        self.saved_search.clear();
+        self.view.clear();
+        self.deferred.clear();
+        self.recent.clear();
+        self.own_flags.clear();
+        self.mail_count = 0;
         Ok(())
     }
-    /// Copy, not implemented
-    pub fn copy(&self, msg: &str) -> Result<()>{
-        todo!();
-        //Err(Error::IMAPCopyErr)
+    /// CLOSE, removes the messages marked `\Deleted` unless the mailbox was opened with EXAMINE and then leaves it as
+    /// UNSELECT does. Nothing is said about the messages removed
+    ///
+    pub fn close(&mut self) -> Result<()>{
+        if !self.read_only {
+            let mut mailbox = self.selected_mailbox()?;
+            let uids: Vec<u32> = mailbox.messages.iter()
+                .filter(|m| m.flags.iter().any(|f| f.eq_ignore_ascii_case("\\Deleted")))
+                .map(|m| m.uid)
+                .collect();
+            mailbox.remove(&uids)?;
+        }
+        self.unselect()
+    }
+    /// Whether a mailbox is selected, commands that work on messages are refused without one
+    ///
+    pub fn has_selected(&self) -> bool {
+        self.selected.is_some()
+    }
+    /// Opens the selected mailbox, errors when none is selected
+    ///
+    pub fn selected_mailbox(&self) -> Result<Mailbox>{
+        let user =  self.username.as_ref().ok_or(Error::FolderLookup("Username Invalid"))?;
+        Mailbox::open(user, self.selected.as_deref().ok_or(Error::NoMailboxSelected)?)
+    }
+    /// Takes expunged messages out of the view, returning the responses that tell the client: EXPUNGE with their
+    /// sequence numbers highest first so each leaves the numbers of the rest unchanged, or a single VANISHED once
+    /// QRESYNC is on. Only called once storage has removed them, so a failed removal leaves the numbering alone
+    ///
+    fn forget(&mut self, uids: &[u32]) -> Vec<String> {
+        let expunged: Vec<(usize, u32)> = self.view.iter().enumerate().rev()
+            .filter(|(_, u)| uids.contains(u))
+            .map(|(index, u)| (index + 1, *u))
+            .collect();
+        self.saved_search.retain(|u| !uids.contains(u));
+        self.view.retain(|u| !uids.contains(u));
+        self.recent.retain(|u| !uids.contains(u));
+        self.mail_count = self.view.len();
+        match self.is_enabled(Extension::QResync) {
+            true if expunged.is_empty() => Vec::new(),
+            true => vec![format!("VANISHED {}", SequenceSet::from_numbers(&expunged.iter().map(|(_, u)| *u).collect::<Vec<_>>()))],
+            false => expunged.iter().map(|(seq, _)| format!("{} EXPUNGE", seq)).collect(),
+        }
+    }
+    /// Resolves a sequence set to the UIDs it covers in mailbox order, `uid` picks whether the numbers are UIDs or
+    /// sequence numbers and `$` is the saved search result. Sequence numbers go through the view and past its end
+    /// are an error, messages expunged by another session that this client has not heard about yet are left out
+    ///
+    fn resolve_set(&self, mailbox: &Mailbox, set: &str, uid: bool) -> Result<Vec<u32>>{
+        if set == "$" {
+            return Ok(mailbox.messages.iter().map(|m| m.uid).filter(|u| self.saved_search.contains(u)).collect())
+        }
+        let set = SequenceSet::try_from(set)?;
+        if uid {
+            let largest = mailbox.messages.last().map_or(0, |m| m.uid);
+            return Ok(mailbox.messages.iter().map(|m| m.uid).filter(|u| set.contains(*u, largest)).collect())
+        }
+        let view = &self.view;
+        if !set.within(view.len() as u32) {
+            return Err(Error::BadArguments("Sequence number beyond the last message"))
+        }
+        Ok(view.iter().enumerate()
+            .filter(|(index, _)| set.contains(*index as u32 + 1, view.len() as u32))
+            .map(|(_, u)| *u)
+            .filter(|u| mailbox.by_uid(*u).is_some())
+            .collect())
     }
-    /// Lists the folders in the mailbox for SELECT queries
+    /// Copies messages from the selected mailbox to another one (COPY and UID COPY), flags and internal dates are kept.
+    /// Returns the destination UIDVALIDITY with the source and destination UID sets for the COPYUID response code
+    ///
+    pub fn copy(&self, msg: &str, uid: bool) -> Result<(u32, SequenceSet, SequenceSet)>{
+        let (source, uids, mut dest) = self.copy_args(msg, uid)?;
+        let pairs = source.copy_to(&uids, &mut dest)?;
+        let (from, to): (Vec<u32>, Vec<u32>) = pairs.into_iter().unzip();
+        Ok((dest.uid_validity, SequenceSet::from_numbers(&from), SequenceSet::from_numbers(&to)))
+    }
+    /// Moves messages (MOVE and UID MOVE, RFC 6851) by copying them and then expunging them from the selected mailbox.
+    /// Returns what [UserSession::copy] does plus the EXPUNGE or VANISHED responses for the messages moved
+    ///
+    pub fn move_messages(&mut self, msg: &str, uid: bool) -> Result<(u32, SequenceSet, SequenceSet, Vec<String>)>{
+        if self.read_only { return Err(Error::ReadOnly) }
+        let (mut source, uids, mut dest) = self.copy_args(msg, uid)?;
+        let pairs = source.move_to(&uids, &mut dest)?;
+        let (from, to): (Vec<u32>, Vec<u32>) = pairs.into_iter().unzip();
+        let expunged = self.forget(&from);
+        Ok((dest.uid_validity, SequenceSet::from_numbers(&from), SequenceSet::from_numbers(&to), expunged))
+    }
+    /// Reads the `sequence-set mailbox` arguments of COPY and MOVE as the selected mailbox, the UIDs to copy and the
+    /// destination mailbox
+    ///
+    fn copy_args(&self, msg: &str, uid: bool) -> Result<(Mailbox, Vec<u32>, Mailbox)>{
+        let user = self.username.as_ref().ok_or(Error::FolderLookup("Username Invalid"))?;
+        let args = parser::parse_args(msg)?;
+        let (set, dest) = match args.as_slice() {
+            [set, dest] => (set.as_str().unwrap_or(""), dest.as_str().ok_or(Error::BadArguments("Missing mailbox"))?),
+            _ => return Err(Error::BadArguments("Expected sequence set and mailbox")),
+        };
+        let source = self.selected_mailbox()?;
+        let uids = self.resolve_set(&source, set, uid)?;
+        Ok((source, uids, Mailbox::open(user, dest)?))
+    }
+    /// Lists the folders in the mailbox for SELECT queries. Takes the LIST-EXTENDED form (RFC 5258)
+    /// `[(selection)] reference pattern(s) [RETURN (options)]` and returns the untagged LIST responses, each followed
+    /// by the mailbox's STATUS when `RETURN (STATUS (...))` asks (RFC 5819). Folders sit side by side so none has
+    /// children, special uses (RFC 6154) are given whether asked for or not
     /// 
     pub fn list(&self, msg: &str) -> Result<Vec<String>>{
-        let folders = self.get_folders().unwrap();
-        let arg = msg.rsplit(r#" "#).next().unwrap().replace("\"", "").to_uppercase();
-        if folders.contains(&arg){
-            return Ok(vec![arg])
+        let user = self.username.clone().ok_or(Error::FolderLookup("Username Invalid"))?;
+        let args = parser::parse_args(msg)?;
+        let (selection, args): (Vec<String>, _) = match args.as_slice() {
+            [Arg::List(selection), rest @ ..] => (selection.iter().filter_map(Arg::as_str).map(|o| o.to_uppercase()).collect(), rest),
+            rest => (Vec::new(), rest),
+        };
+        let (reference, patterns, returns) = match args {
+            [reference, Arg::List(patterns), rest @ ..] => (reference, patterns.iter().collect::<Vec<_>>(), rest),
+            [reference, pattern, rest @ ..] => (reference, vec![pattern], rest),
+            _ => return Err(Error::BadArguments("Expected reference and mailbox name")),
+        };
+        let returns = match returns {
+            [] => &[][..],
+            [keyword, Arg::List(returns)] if keyword.is_atom("RETURN") => returns.as_slice(),
+            _ => return Err(Error::BadArguments("Expected RETURN options")),
+        };
+        if selection.iter().any(|o| !["SUBSCRIBED", "REMOTE", "RECURSIVEMATCH", "SPECIAL-USE"].contains(&o.as_str())) {
+            return Err(Error::BadArguments("Unknown LIST selection option"))
         }
-        match arg.as_str() {
-            ""      => return Ok(vec![r#""""#.into()]),
-            "%/%"   => return Ok(vec![r#""""#.into()]),
-            "%"     => return Ok(folders),
-            "*"     => return Ok(folders),
-            _       => return Ok(Vec::new()),
+        let selects = |option: &str| selection.iter().any(|o| o == option);
+        // RECURSIVEMATCH only refines another selection option (RFC 5258 3)
+        if selects("RECURSIVEMATCH") && !selects("SUBSCRIBED") && !selects("SPECIAL-USE") {
+            return Err(Error::BadArguments("RECURSIVEMATCH needs another selection option"))
+        }
+        let mut return_subscribed = false;
+        let mut status_items: Option<Vec<String>> = None;
+        let mut returns = returns.iter();
+        while let Some(option) = returns.next() {
+            match option.as_str().map(|o| o.to_uppercase()).as_deref() {
+                Some("SUBSCRIBED") => return_subscribed = true,
+                Some("CHILDREN") | Some("SPECIAL-USE") => {}
+                Some("STATUS") => {
+                    let Some(Arg::List(items)) = returns.next() else { return Err(Error::BadArguments("Expected STATUS items")) };
+                    status_items = Some(items.iter().filter_map(Arg::as_str).map(|i| i.to_uppercase()).collect());
+                }
+                _ => return Err(Error::BadArguments("Unknown LIST return option")),
+            }
+        }
+
+        let reference = reference.as_str().ok_or(Error::BadArguments("Expected reference"))?;
+        let patterns: Vec<String> = patterns.iter().filter_map(|p| p.as_str()).map(|p| format!("{}{}", reference, p)).collect();
+        // An empty name asks for the hierarchy delimiter
+        if selection.is_empty() && patterns.iter().all(|p| p.is_empty()) {
+            return Ok(vec![r#"LIST (\Noselect \HasChildren) "/" """#.into()])
+        }
+
+        let folders = self.get_folders()?;
+        let subscribed = self.subscribed(&user)?;
+        let is_subscribed = |name: &str| subscribed.iter().any(|s| s.eq_ignore_ascii_case(name));
+        let mut names: Vec<(String, bool)> = folders.iter().map(|f| (f.clone(), true)).collect();
+        if selects("SUBSCRIBED") {
+            // A subscription to a mailbox that is gone is still listed, as \NonExistent
+            names.retain(|(name, _)| is_subscribed(name));
+            names.extend(subscribed.iter().filter(|s| !folders.iter().any(|f| f.eq_ignore_ascii_case(s))).map(|s| (s.clone(), false)));
+        }
+
+        let mut responses = Vec::new();
+        for (name, exists) in names {
+            let display = self.mailbox_name(&name);
+            if !patterns.iter().any(|p| pattern_matches(p, &display)) { continue }
+            let special = special_use(&name).filter(|_| exists);
+            if selects("SPECIAL-USE") && special.is_none() { continue }
+            let mut attributes = match exists {
+                true => vec!["\\Marked", "\\HasNoChildren"],
+                false => vec!["\\NonExistent"],
+            };
+            attributes.extend(special);
+            if (selects("SUBSCRIBED") || return_subscribed) && is_subscribed(&name) { attributes.push("\\Subscribed") }
+            responses.push(format!("LIST ({}) \"/\" {}", attributes.join(" "), fetch::imap_string(&display)));
+            if let (Some(items), true) = (&status_items, exists) {
+                let mailbox = Mailbox::open(&user, &name)?;
+                self.check_status_items(&mailbox, items)?;
+                responses.push(status_response(&display, &mailbox, &items.iter().map(|i| i.as_str()).collect::<Vec<_>>()));
+            }
+        }
+        Ok(responses)
+    }
+    /// Whether the mailbox named in CREATE is already there, new folders are only made on the server itself
+    ///
+    pub fn mailbox_exists(&self, msg: &str) -> Result<bool>{
+        let user = self.username.clone().ok_or(Error::FolderLookup("Username Invalid"))?;
+        let args = parser::parse_args(msg)?;
+        let name = args.first().and_then(Arg::as_str).ok_or(Error::BadArguments("Expected mailbox name"))?;
+        Ok(storage::mailbox_path(&user, name).is_some())
+    }
+    /// SUBSCRIBE and UNSUBSCRIBE, names are kept as the folder is called when it exists. The first change starts from
+    /// every folder being subscribed
+    ///
+    pub fn subscribe(&mut self, msg: &str, subscribe: bool) -> Result<()>{
+        let user = self.username.clone().ok_or(Error::FolderLookup("Username Invalid"))?;
+        let args = parser::parse_args(msg)?;
+        let [name] = args.as_slice() else { return Err(Error::BadArguments("Expected mailbox name")) };
+        let name = self.folder_name(&user, name.as_str().ok_or(Error::BadArguments("Expected mailbox name"))?);
+        let mut names = self.subscribed(&user)?;
+        names.retain(|n| !n.eq_ignore_ascii_case(&name));
+        if subscribe { names.push(name) }
+        storage::save_subscriptions(&user, &names)?;
+        changes::record_subscriptions();
+        // A client is not told about its own changes
+        if self.subscriptions.1 + 1 == changes::subscriptions_version() {
+            self.subscriptions = (names, changes::subscriptions_version());
+            if let Some(notify) = self.notify.clone() { self.rewatch(&notify, &user) }
+        }
+        Ok(())
+    }
+    /// The mailboxes a user is subscribed to, every folder until they first change that
+    ///
+    fn subscribed(&self, user: &str) -> Result<Vec<String>>{
+        match storage::subscriptions(user) {
+            Some(names) => Ok(names),
+            None => self.get_folders(),
         }
     }
     ///Gets all folders and puts them into array
     /// 
     fn get_folders(&self) -> Result<Vec<String>>{
-
-        let path = format!("{}/mail/{}", MAIL_ROOT, self.username.as_ref().unwrap());
-        let mut dir = fs::read_dir(path).unwrap();
-
+        let user = self.username.as_ref().ok_or(Error::FolderLookup("Username Invalid"))?;
         let mut folders: Vec<String> = vec![];
-        
-        while let Some(f) = dir.next(){
-            let file = f.unwrap();
-            if file.metadata().unwrap().is_dir() {
-                let name = file.file_name().into_string().unwrap();
-                folders.push(name);
-            }
+        for entry in std::fs::read_dir(storage::user_root(user)).map_err(Error::IO)? {
+            let entry = entry.map_err(Error::IO)?;
+            // A folder removed while the directory is read is simply left out, as are names that are not UTF-8
+            if !entry.metadata().is_ok_and(|m| m.is_dir()) { continue }
+            if let Ok(name) = entry.file_name().into_string() { folders.push(name) }
         }
         Ok(folders)
     }
-    /// Search UID
+    /// SEARCH and UID SEARCH, returns the sequence numbers or UIDs of the messages in the selected mailbox matching the keys
+    /// along with any `RETURN (...)` options, which ask for an ESEARCH response. `SAVE` keeps the result for `$`. When
+    /// the keys use MODSEQ the highest MODSEQ of the matches is returned too. IMAP4rev2 clients always get ESEARCH,
+    /// with no options being `RETURN (ALL)`
     /// 
-    pub fn search(&self, msg: &str) -> Result<Vec<String>>{
-        let date = msg.rsplitn(2," ").next().unwrap();
+    pub fn search(&mut self, msg: &str, uid: bool) -> Result<SearchResult>{
+        let mut args = parser::parse_args(msg)?;
+        let returns = search::take_return_options(&mut args)?
+            .or_else(|| self.is_enabled(Extension::Imap4Rev2).then(|| vec![ReturnOption::All]));
+        let save = returns.as_ref().is_some_and(|r| r.contains(&ReturnOption::Save));
+        // A failed SAVE search leaves the saved result empty (RFC 5182)
+        if save { self.saved_search.clear() }
 
-        let mut split_date = date.split("-");
-        let day = split_date.next().unwrap();
-        let month: crate::types::Month = split_date.next().unwrap().try_into().unwrap();
-        let year = split_date.next().unwrap();
-        let email_timestamp = Utc.ymd(year.parse().unwrap(), month as u32, day.parse().unwrap()).and_hms(0, 0, 0).timestamp();
+        // Search strings arrive as UTF-8, which US-ASCII is a subset of, other charsets are refused with BADCHARSET
+        if args.first().is_some_and(|a| a.is_atom("CHARSET")) {
+            let charset = args.get(1).and_then(Arg::as_str).unwrap_or("");
+            if !charset.eq_ignore_ascii_case("UTF-8") && !charset.eq_ignore_ascii_case("US-ASCII") {
+                return Err(Error::BadCharset)
+            }
+            args.drain(..2);
+        }
+        let key = SearchKey::parse(&args)?;
+        if key.uses_modseq() { self.turn_on(Extension::CondStore); }
 
-        let path = format!("{}/mail/{}/Inbox", MAIL_ROOT, self.username.as_ref().unwrap());
-        let dir = fs::read_dir(path).unwrap();
+        let mailbox = self.selected_mailbox()?;
+        let limit = self.memory_limit;
+        let view = &self.view;
+        let largest_uid = mailbox.messages.last().map_or(0, |m| m.uid);
+        let mut matched: Vec<(u32, u32)> = Vec::new();
+        let mut highest_modseq = 0;
+        for entry in mailbox.messages.iter() {
+            // Messages this client has not been told about yet have no sequence number to answer with
+            let Ok(index) = view.binary_search(&entry.uid) else { continue };
+            let seq = index as u32 + 1;
+            // Messages that cannot be read simply do not match keys that need their contents
+            let email = match key.needs_email() {
+                true => Email::load(&entry.uid.to_string(), &seq.to_string(), mailbox.message_path(entry), limit).ok(),
+                false => None,
+            };
+            let candidate = Candidate{
+                seq,
+                entry,
+                email: email.as_ref(),
+                largest_seq: view.len() as u32,
+                largest_uid,
+                saved: &self.saved_search,
+                recent: self.recent.contains(&entry.uid),
+            };
+            if key.matches(&candidate) {
+                matched.push((seq, entry.uid));
+                highest_modseq = highest_modseq.max(entry.modseq);
+            }
+        }
 
-        let search_results: Vec<_> = dir.into_iter().filter(|f| {
-            let file_date: f64 = f.as_ref().unwrap().file_name().into_string().unwrap().split("s.eml").next().unwrap().parse().unwrap();
-            file_date > email_timestamp as f64
-        }).collect();
+        let results: Vec<u32> = matched.iter().map(|(seq, u)| if uid { *u } else { *seq }).collect();
+        if let Some(returns) = returns.as_ref().filter(|_| save) {
+            // The saved result is kept as UIDs so it stays correct if sequence numbers shift
+            let saved = search::saved_results(&results, returns);
+            self.saved_search = matched.iter()
+                .filter(|(seq, u)| saved.contains(if uid { u } else { seq }))
+                .map(|(_, u)| *u)
+                .collect();
+        }
+        Ok(SearchResult{ matches: results, returns, highest_modseq: key.uses_modseq().then_some(highest_modseq) })
+    }
+    /// Changes flags for STORE and UID STORE. Returns the untagged FETCH responses for the messages in the set, none
+    /// for the `.SILENT` forms, and the sequence numbers or UIDs `(UNCHANGEDSINCE modseq)` refused for `[MODIFIED]`
+    ///
+    pub fn store(&mut self, msg: &str, uid: bool) -> Result<(Vec<String>, Vec<u32>)>{
+        if self.read_only { return Err(Error::ReadOnly) }
+        let mut args = parser::parse_args(msg)?;
+        let unchanged_since = match args.get(1) {
+            Some(Arg::List(modifiers)) if args.len() > 3 => {
+                let since = match modifiers.as_slice() {
+                    [name, value] if name.is_atom("UNCHANGEDSINCE") => value.as_str().and_then(|v| v.parse().ok()),
+                    _ => None,
+                };
+                args.remove(1);
+                self.turn_on(Extension::CondStore);
+                Some(since.ok_or(Error::BadArguments("Expected (UNCHANGEDSINCE mod-sequence)"))?)
+            }
+            _ => None,
+        };
+        let (set, item, flags) = match args.as_slice() {
+            [set, item, Arg::List(flags)] => (set, item, flags.iter().filter_map(Arg::as_str).collect::<Vec<_>>()),
+            [set, item, flag] => (set, item, flag.as_str().into_iter().collect()),
+            _ => return Err(Error::BadArguments("Expected sequence set, data item and flags")),
+        };
+        let item = item.as_str().ok_or(Error::BadArguments("Invalid STORE data item"))?.to_uppercase();
+        let silent = item.ends_with(".SILENT");
+        let mode = match item.trim_end_matches(".SILENT") {
+            "FLAGS" => StoreMode::Replace,
+            "+FLAGS" => StoreMode::Add,
+            "-FLAGS" => StoreMode::Remove,
+            _ => return Err(Error::BadArguments("Invalid STORE data item")),
+        };
 
-        let uids: Vec<_> = search_results.into_iter().map(| f |{
-            self.filename_to_uid(f.as_ref().unwrap())
-        }).collect();
+        let mut mailbox = self.selected_mailbox()?;
+        let uids = self.resolve_set(&mailbox, set.as_str().unwrap_or(""), uid)?;
+        let (changed, refused) = mailbox.store_flags(&uids, &flags, mode, unchanged_since)?;
+        self.own_flags.extend(&changed);
 
-        Ok(uids)
+        let view = &self.view;
+        let number = |u: &u32| match uid {
+            true => Some(*u),
+            false => Some(view.binary_search(u).ok()? as u32 + 1),
+        };
+        let modified = refused.iter().filter_map(number).collect();
+        if silent { return Ok((Vec::new(), modified)) }
+        let responses = uids.iter()
+            .filter(|u| !refused.contains(u))
+            .filter_map(|u| {
+                let (seq, entry) = (view.binary_search(u).ok()? + 1, mailbox.by_uid(*u)?);
+                Some(format!("{} FETCH ({})", seq, self.flag_items(entry, uid)))
+            })
+            .collect();
+        Ok((responses, modified))
     }
-    /// Fetch UID
-    /// 
-    pub fn fetch_uid(&self, msg: &str) -> Result<Vec<String>>{
-        // Holding Vector for result
-        let mut emails: Vec<Email> = Vec::new();
-        let mut responses: Vec<String> = Vec::new();
-        let mut fetch_all = false;
-
-        // Split it into two parts, sequence number(s) and args
-        let mut split = msg.splitn(2, " ");
-        let uid = split.next().unwrap();
-        let args = split.next().unwrap();
-
-        // Get the files in the Inbox
-        let path = format!("{}/mail/{}/Inbox", MAIL_ROOT, self.username.as_ref().unwrap());
-        let dir = fs::read_dir(path).unwrap();
-
-        // Get the UID list
-        let mut uids: Vec<String> = Vec::new(); 
-
-        if uid.contains(",") {
-            let mut uid_list = uid.split(",");
-            while let Some(uid) = uid_list.next(){ 
-                uids.push(uid.into());
+    /// Removes the messages flagged `\Deleted` (EXPUNGE), UID EXPUNGE (RFC 4315) only removes those also in its UID
+    /// set. Returns the EXPUNGE or VANISHED responses to send, a mailbox opened with EXAMINE gives [Error::ReadOnly]
+    ///
+    pub fn expunge(&mut self, msg: &str, uid: bool) -> Result<Vec<String>>{
+        if self.read_only { return Err(Error::ReadOnly) }
+        let mut mailbox = self.selected_mailbox()?;
+        let in_set = match uid {
+            true => self.resolve_set(&mailbox, msg, true)?,
+            false => mailbox.messages.iter().map(|m| m.uid).collect(),
+        };
+        let uids: Vec<u32> = mailbox.messages.iter()
+            .filter(|m| m.flags.iter().any(|f| f.eq_ignore_ascii_case("\\Deleted")))
+            .filter(|m| in_set.binary_search(&m.uid).is_ok())
+            .map(|m| m.uid)
+            .collect();
+        mailbox.remove(&uids)?;
+        Ok(self.forget(&uids))
+    }
+    /// Untagged responses for changes to the selected mailbox since this client was last told, whether made by other
+    /// sessions or by mail dropped into the folder. Expunges come first as they renumber what follows, but when
+    /// `expunge` is false (during FETCH, STORE and SEARCH, RFC 3501 7.4.1) they are held back for a later command
+    ///
+    pub fn pending_updates(&mut self, expunge: bool) -> Result<Vec<String>>{
+        self.updates(expunge, true)
+    }
+    /// [UserSession::pending_updates], leaving out FETCH FLAGS unless `flags` is set
+    ///
+    fn updates(&mut self, expunge: bool, flags: bool) -> Result<Vec<String>>{
+        if self.selected.is_none() { return Ok(Vec::new()) }
+        let mailbox = self.selected_mailbox()?;
+        let (found, position) = match mailbox.changes_since(self.position) {
+            Some(found) => found,
+            None => (self.resync(&mailbox), mailbox.change_position()),
+        };
+        self.position = position;
+
+        let mut responses = Vec::new();
+        self.deferred.extend(found.iter().filter_map(|c| match c {
+            Change::Expunged(uid) if self.view.contains(uid) => Some(*uid),
+            _ => None,
+        }));
+        if expunge {
+            let deferred = std::mem::take(&mut self.deferred);
+            responses.extend(self.forget(&deferred));
+        }
+
+        let mut added = false;
+        let mut flagged: Vec<u32> = Vec::new();
+        for change in found {
+            match change {
+                Change::Added(uid) => if !self.view.contains(&uid) && mailbox.by_uid(uid).is_some() {
+                    self.view.push(uid);
+                    self.recent.push(uid);
+                    added = true;
+                }
+                Change::Flags(uid) => match self.own_flags.iter().position(|&u| u == uid) {
+                    Some(index) => { self.own_flags.remove(index); }
+                    None => if !flagged.contains(&uid) { flagged.push(uid) },
+                }
+                Change::Expunged(_) => {}
             }
-        }else if uid.contains(":"){
-            fetch_all = true;
-        }
-        else{
-            // Lookup one thing
-            uids.push(uid.into());
-        }
-        
-        for (index, file) in dir.enumerate(){
-            let file_uid = self.filename_to_uid(file.as_ref().unwrap());
-            if fetch_all == true{
-                emails.push(Email::new(&file_uid, &index.to_string(), file.as_ref().unwrap().path()).unwrap());
+        }
+        if added {
+            responses.push(format!("{} EXISTS", self.view.len()));
+            // IMAP4rev2 dropped RECENT
+            if !self.is_enabled(Extension::Imap4Rev2) { responses.push(format!("{} RECENT", self.recent.len())) }
+        }
+        for uid in flagged.into_iter().filter(|_| flags) {
+            let (Ok(index), Some(entry)) = (self.view.binary_search(&uid), mailbox.by_uid(uid)) else { continue };
+            responses.push(format!("{} FETCH ({})", index + 1, self.flag_items(entry, false)));
+        }
+        self.mail_count = self.view.len();
+        Ok(responses)
+    }
+    /// Sets or clears the NOTIFY subscription (RFC 5465), returning the STATUS responses the `STATUS` option asks for
+    ///
+    pub fn notify(&mut self, msg: &str) -> Result<Vec<String>>{
+        let user = self.username.clone().ok_or(Error::FolderLookup("Username Invalid"))?;
+        let notify = Notify::parse(&parser::parse_args(msg)?)?;
+        self.watched.clear();
+        let mut responses = Vec::new();
+        if let Some(notify) = &notify {
+            self.folders = self.get_folders()?;
+            self.subscriptions = (self.subscribed(&user)?, changes::subscriptions_version());
+            for name in self.folders.iter().filter(|n| notify.events_for(n, &self.subscriptions.0).is_some()) {
+                let mailbox = Mailbox::open(&user, name)?;
+                self.watched.push((name.clone(), mailbox.path().to_path_buf(), mailbox.change_position()));
+                if notify.status && !self.is_selected(name) { responses.push(status_line(name, &mailbox, &self.enabled)) }
             }
-            else if uids.contains(&file_uid){
-                emails.push(Email::new(&file_uid, &index.to_string(), file.as_ref().unwrap().path()).unwrap());
+        }
+        self.notify = notify;
+        Ok(responses)
+    }
+    /// Whether a NOTIFY subscription is active, events are then sent between commands too
+    ///
+    pub fn notifying(&self) -> bool {
+        self.notify.is_some()
+    }
+    /// Events for the NOTIFY subscription: updates for the selected mailbox, STATUS for other watched mailboxes that
+    /// changed, LIST for folders that appeared or went away and for subscriptions changed by other sessions. Only
+    /// mailboxes whose change log moved are reopened unless `rescan` asks for all of them, mail dropped into a
+    /// folder by something else and new folders are only found then
+    ///
+    pub fn notify_events(&mut self, rescan: bool) -> Result<Vec<String>>{
+        let Some(notify) = self.notify.clone() else { return Ok(Vec::new()) };
+        let user = self.username.clone().ok_or(Error::FolderLookup("Username Invalid"))?;
+        let mut responses = Vec::new();
+        if let Some((events, delayed)) = notify.selected().filter(|(events, _)| !events.is_empty()) {
+            let moved = || self.selected.as_deref()
+                .and_then(|name| storage::mailbox_path(&user, name))
+                .is_none_or(|path| changes::position(&path) != self.position);
+            if rescan || !self.deferred.is_empty() || moved() {
+                responses.extend(self.updates(!delayed, events.contains(&Event::FlagChange))?);
             }
         }
 
-        // Format the emails
-        for email in emails{
-            responses.push(email.format_response(args))
+        if rescan || self.subscriptions.1 != changes::subscriptions_version() {
+            let version = changes::subscriptions_version();
+            let names = self.subscribed(&user)?;
+            if notify.wants(Event::SubscriptionChange) {
+                let (told, _) = &self.subscriptions;
+                for name in names.iter().filter(|n| !told.iter().any(|t| t.eq_ignore_ascii_case(n))) {
+                    responses.push(format!("LIST (\\Subscribed) \"/\" {}", fetch::imap_string(&self.mailbox_name(name))));
+                }
+                for name in told.iter().filter(|t| !names.iter().any(|n| n.eq_ignore_ascii_case(t))) {
+                    responses.push(format!("LIST () \"/\" {}", fetch::imap_string(&self.mailbox_name(name))));
+                }
+            }
+            self.subscriptions = (names, version);
+            self.rewatch(&notify, &user);
+        }
+
+        if notify.wants(Event::MailboxName) && rescan {
+            let folders = self.get_folders()?;
+            for name in folders.iter().filter(|n| !self.folders.contains(n)) {
+                responses.push(format!("LIST () \"/\" {}", fetch::imap_string(&self.mailbox_name(name))));
+                if let (Some(_), Some(path)) = (notify.events_for(name, &self.subscriptions.0), storage::mailbox_path(&user, name)) {
+                    self.watched.push((name.clone(), path, 0));
+                }
+            }
+            for name in self.folders.iter().filter(|n| !folders.contains(n)) {
+                responses.push(format!("LIST (\\NonExistent) \"/\" {}", fetch::imap_string(&self.mailbox_name(name))));
+            }
+            self.watched.retain(|(name, _, _)| folders.contains(name));
+            self.folders = folders;
+        }
+
+        let selected = self.selected.clone().unwrap_or_default();
+        let enabled = self.enabled.clone();
+        for (name, path, position) in self.watched.iter_mut() {
+            if !rescan && changes::position(path) == *position { continue }
+            let (Some(events), Ok(mailbox)) = (notify.events_for(name, &self.subscriptions.0), Mailbox::open(&user, name)) else { continue };
+            let changed = match mailbox.changes_since(*position) {
+                Some((found, _)) => found.iter().any(|change| events.contains(&match change {
+                    Change::Added(_) => Event::MessageNew,
+                    Change::Expunged(_) => Event::MessageExpunge,
+                    Change::Flags(_) => Event::FlagChange,
+                })),
+                None => true,
+            };
+            *position = mailbox.change_position();
+            if changed && !name.eq_ignore_ascii_case(&selected) { responses.push(status_line(name, &mailbox, &enabled)) }
         }
-        // Parse Args
         Ok(responses)
     }
-    /// Fetch (Non UID version)
+    /// Brings the mailboxes NOTIFY watches in line with the folders and subscriptions last seen. Mailboxes still watched
+    /// keep how far through their change log the client has been told, newly covered ones start from now
+    ///
+    fn rewatch(&mut self, notify: &Notify, user: &str) {
+        let watched = std::mem::take(&mut self.watched);
+        for name in self.folders.iter().filter(|n| notify.events_for(n, &self.subscriptions.0).is_some()) {
+            match watched.iter().find(|(w, _, _)| w == name) {
+                Some(entry) => self.watched.push(entry.clone()),
+                None => if let Some(path) = storage::mailbox_path(user, name) {
+                    let position = changes::position(&path);
+                    self.watched.push((name.clone(), path, position));
+                },
+            }
+        }
+    }
+    /// STATUS, the untagged response with the items asked for of a mailbox. Asking for HIGHESTMODSEQ turns on CONDSTORE
+    ///
+    pub fn status(&mut self, msg: &str) -> Result<String>{
+        let user = self.username.clone().ok_or(Error::FolderLookup("Username Invalid"))?;
+        let args = parser::parse_args(msg)?;
+        let (name, items) = match args.as_slice() {
+            [name, Arg::List(items)] => (name.as_str().ok_or(Error::BadArguments("Expected mailbox name"))?, items),
+            _ => return Err(Error::BadArguments("Expected mailbox name and status items")),
+        };
+        let items: Vec<String> = items.iter().filter_map(Arg::as_str).map(|i| i.to_uppercase()).collect();
+        let mailbox = Mailbox::open(&user, name)?;
+        self.check_status_items(&mailbox, &items)?;
+        if items.iter().any(|i| i == "HIGHESTMODSEQ") { self.turn_on(Extension::CondStore); }
+        Ok(status_response(name, &mailbox, &items.iter().map(|i| i.as_str()).collect::<Vec<_>>()))
+    }
+    /// Refuses STATUS items this server does not know, and RECENT once IMAP4rev2 is on as it was dropped there
+    ///
+    fn check_status_items(&self, mailbox: &Mailbox, items: &[String]) -> Result<()>{
+        let rev2 = self.is_enabled(Extension::Imap4Rev2);
+        if items.iter().any(|i| status_item(mailbox, i).is_none() || (rev2 && i == "RECENT")) {
+            return Err(Error::BadArguments("Unknown STATUS item"))
+        }
+        Ok(())
+    }
+    /// The items of a FETCH response reporting new flags, with the UID when asked for or QRESYNC is on and the MODSEQ
+    /// once CONDSTORE is
+    ///
+    fn flag_items(&self, entry: &MessageEntry, uid: bool) -> String {
+        let mut items = vec![format!("FLAGS ({})", entry.flags.join(" "))];
+        if uid || self.is_enabled(Extension::QResync) { items.insert(0, format!("UID {}", entry.uid)) }
+        if self.is_enabled(Extension::CondStore) { items.push(format!("MODSEQ ({})", entry.modseq)) }
+        items.join(" ")
+    }
+    /// Whether a mailbox name is the selected mailbox
+    ///
+    fn is_selected(&self, name: &str) -> bool {
+        self.selected.as_deref().is_some_and(|s| s.eq_ignore_ascii_case(name))
+    }
+    /// Works out the changes by comparing the view with the mailbox, for a client that fell behind the change log
+    ///
+    fn resync(&self, mailbox: &Mailbox) -> Vec<Change> {
+        let expunged = self.view.iter().filter(|u| mailbox.by_uid(**u).is_none()).map(|u| Change::Expunged(*u));
+        let present = mailbox.messages.iter().map(|m| match self.view.contains(&m.uid) {
+            true => Change::Flags(m.uid),
+            false => Change::Added(m.uid),
+        });
+        expunged.chain(present).collect()
+    }
+    /// Fetch UID, each response is passed to `write` as soon as it is built
     /// 
-    pub fn fetch_seq(&self, msg: &str) -> Result<Vec<String>>{
-        // Holding Vector for result
-        let mut emails: Vec<Email> = Vec::new();
-        let mut responses: Vec<String> = Vec::new();
-
-        // Split it into two parts, sequence number(s) and args
-        let mut split = msg.splitn(2, " ");
-        let seq = split.next().unwrap();
-        let args = split.next().unwrap();
-
-        // Get the files in the Inbox
-        let path = format!("{}/mail/{}/Inbox", MAIL_ROOT, self.username.as_ref().unwrap());
-        let dir = fs::read_dir(path).unwrap();
-
-        if seq.contains(",") {
-            // Look up Vector
-            emails.append(&mut self.fetch_list(seq, dir).unwrap());
-        }
-        else if seq.contains(":") {
-            // Lookup Range
-            emails.append(&mut self.fetch_range(seq, dir).unwrap());
-        }
-        else{
-            // Lookup one thing
-            emails.push(self.fetch_one(seq, dir).unwrap());
-        }
-            // Formate the
-        for email in emails{
-            responses.push(email.format_response(args))
-        }
-        
-        // Parse Args
-        Ok(responses)
+    pub fn fetch_uid(&mut self, msg: &str, write: impl FnMut(Vec<Chunk>) -> Result<()>) -> Result<()>{
+        self.fetch(msg, true, write)
     }
-    /// Fetch one from Sequence number
+    /// Fetch (Non UID version), each response is passed to `write` as soon as it is built
     /// 
-    fn fetch_one(&self, seq: &str, mut dir: fs::ReadDir) -> Result<Email> {
-        let seq_num: usize = seq.parse().unwrap();
-        let file = dir.nth(seq_num-1).unwrap().unwrap();
-        let uid = self.filename_to_uid(&file);
-        Ok(Email::new(&uid, &seq, file.path()).unwrap())
+    pub fn fetch_seq(&mut self, msg: &str, write: impl FnMut(Vec<Chunk>) -> Result<()>) -> Result<()>{
+        self.fetch(msg, false, write)
     }
-    /// Fetch Range from Sequence number
+    /// Resolves the sequence set of a FETCH and writes the responses, UID FETCH always answers with the UID
     ///
-    fn fetch_range(&self, seq: &str, dir: fs::ReadDir) -> Result<Vec<Email>> {
-        let mut res: Vec<Email> = Vec::new();
-
-        let mut split = seq.splitn(2, ":");
-        let from = split.next().unwrap().parse().unwrap();
-        let to = split.next().unwrap().parse().unwrap();
-
-        for (index, file) in dir.enumerate(){
-            let index = index + 1;
-            if index < from { continue };
-            if index > to { break };
-            
-            let uid = self.filename_to_uid(&file.as_ref().unwrap());
-            res.push(Email::new(&uid, &index.to_string(), file.unwrap().path()).unwrap());
+    fn fetch(&mut self, msg: &str, uid: bool, mut write: impl FnMut(Vec<Chunk>) -> Result<()>) -> Result<()>{
+        let (set, args) = msg.trim().split_once(' ').ok_or(Error::BadArguments("Missing FETCH attributes"))?;
+        let mut mailbox = self.selected_mailbox()?;
+        let mut uids = self.resolve_set(&mailbox, set, uid)?;
+        let (attrs, modifiers) = fetch::parse_attributes(args, uid)?;
+        if attrs.contains(&FetchAttr::ModSeq) { self.turn_on(Extension::CondStore); }
+        if let Some(since) = modifiers.changed_since {
+            uids.retain(|u| mailbox.by_uid(*u).is_some_and(|m| m.modseq > since));
+        }
+        // UID FETCH (VANISHED) first reports what was expunged from the set since CHANGEDSINCE (RFC 7162 3.2.6)
+        if modifiers.vanished {
+            if !uid || !self.is_enabled(Extension::QResync) { return Err(Error::BadArguments("VANISHED needs UID FETCH with QRESYNC enabled")) }
+            let set = SequenceSet::try_from(set)?;
+            let largest = mailbox.uid_next.saturating_sub(1);
+            let vanished = vanished_since(&mailbox, modifiers.changed_since.unwrap_or(0), |u| set.contains(u, largest));
+            if !vanished.is_empty() {
+                let line = format!("VANISHED (EARLIER) {}\r\n", SequenceSet::from_numbers(&vanished));
+                write(vec![Chunk::Bytes(line.into_bytes())])?;
+            }
         }
-        Ok(res)
+        let marked = self.fetch_responses(&uids, &attrs, &mut mailbox, write)?;
+        self.own_flags.extend(marked);
+        Ok(())
     }
-    /// Fetch list from Sequence number
+    /// Builds the FETCH responses one message at a time so only one is held in memory. Fetching a body without PEEK
+    /// sets `\Seen` on unseen messages first, and their responses carry the new FLAGS even when the client did not ask.
+    /// `uids` are in mailbox order as [UserSession::resolve_set] gives them, messages this client has not been told
+    /// about yet are skipped. Returns the messages marked seen
     ///
-    fn fetch_list(&self, seq: &str, dir: fs::ReadDir) -> Result<Vec<Email>> {
-        let mut res: Vec<Email> = Vec::new();
-        let mut seqs: Vec<String> = Vec::new();
+    fn fetch_responses(&self, uids: &[u32], attrs: &[FetchAttr], mailbox: &mut Mailbox, mut write: impl FnMut(Vec<Chunk>) -> Result<()>) -> Result<Vec<u32>>{
+        let view = &self.view;
+        let limit = self.memory_limit;
+        let uids: Vec<u32> = uids.iter().copied().filter(|u| view.binary_search(u).is_ok()).collect();
+        let marked: Vec<u32> = match fetch::sets_seen(attrs) && !self.read_only {
+            true => uids.iter().filter_map(|&uid| mailbox.by_uid(uid))
+                .filter(|m| !m.flags.iter().any(|f| f.eq_ignore_ascii_case("\\Seen")))
+                .map(|m| m.uid).collect(),
+            false => Vec::new(),
+        };
+        if !marked.is_empty() {
+            mailbox.store_flags(&marked, &["\\Seen"], StoreMode::Add, None)?;
+        }
+
+        let mut with_flags = attrs.to_vec();
+        if !with_flags.contains(&FetchAttr::Flags) { with_flags.push(FetchAttr::Flags) }
+        if self.is_enabled(Extension::CondStore) && !with_flags.contains(&FetchAttr::ModSeq) { with_flags.push(FetchAttr::ModSeq) }
+        if self.is_enabled(Extension::QResync) && !with_flags.contains(&FetchAttr::Uid) { with_flags.insert(0, FetchAttr::Uid) }
 
-        let mut seq_list = seq.splitn(2, " ").next().unwrap().split(",");
-        let seq_count = seq_list.clone().count();
-        
-        while let Some(seq) = seq_list.next(){   
-            seqs.push(seq.into());
+        for message in mailbox.messages.iter() {
+            if uids.binary_search(&message.uid).is_err() { continue }
+            let Ok(index) = view.binary_search(&message.uid) else { continue };
+            let response = Email::load(&message.uid.to_string(), &(index + 1).to_string(), mailbox.message_path(message), limit)
+                .and_then(|email| {
+                    let email = email.with_flags(message.flags.clone())
+                        .with_internal_date(message.internal_date)
+                        .with_modseq(message.modseq)
+                        .with_utf8(self.is_enabled(Extension::Utf8Accept));
+                    match marked.contains(&message.uid) {
+                        true => email.format_response(&with_flags),
+                        false => email.format_response(attrs),
+                    }
+                });
+            // A file removed since the index was read costs the client that one message rather than the connection
+            let unreadable = || vec![Chunk::Bytes(format!("NO Message {} could not be read\r\n", index + 1).into_bytes())];
+            match response {
+                Ok(response) => match write(response) {
+                    Err(Error::CantReadEmail) => write(unreadable())?,
+                    result => result?,
+                },
+                Err(Error::IO(_)) => write(unreadable())?,
+                Err(e) => return Err(e),
+            }
         }
+        Ok(marked)
+    }
+}
+
+/// Parses the `(uidvalidity modseq [known-uids [(seq-match-data)]])` of SELECT (QRESYNC ...). The sequence match data
+/// only helps servers that forget expunges so it is accepted and left unused
+///
+fn parse_qresync(values: &[Arg]) -> Result<(u32, u64, Option<SequenceSet>)> {
+    let bad = || Error::BadArguments("Invalid QRESYNC parameters");
+    let number = |index: usize| values.get(index).and_then(Arg::as_str).ok_or_else(bad);
+    let uid_validity = number(0)?.parse().map_err(|_| bad())?;
+    let modseq = number(1)?.parse().map_err(|_| bad())?;
+    let known = match values.get(2) {
+        Some(Arg::List(_)) | None => None,
+        Some(known) => Some(SequenceSet::try_from(known.as_str().ok_or_else(bad)?)?),
+    };
+    Ok((uid_validity, modseq, known))
+}
+
+/// The UIDs expunged since a mod-sequence that `known` covers. When the expunge log no longer reaches back that far
+/// every UID below UIDNEXT that is not in the mailbox is reported instead, which RFC 7162 allows
+///
+fn vanished_since(mailbox: &Mailbox, modseq: u64, known: impl Fn(u32) -> bool) -> Vec<u32> {
+    match mailbox.vanished_since(modseq) {
+        Some(uids) => uids.into_iter().filter(|u| known(*u)).collect(),
+        None => (1..mailbox.uid_next).filter(|u| known(*u) && mailbox.by_uid(*u).is_none()).collect(),
+    }
+}
+
+/// The untagged STATUS NOTIFY sends for a mailbox other than the selected one, with HIGHESTMODSEQ once CONDSTORE is on
+/// and the name in modified UTF-7 until UTF8=ACCEPT or IMAP4rev2 is
+///
+fn status_line(name: &str, mailbox: &Mailbox, enabled: &[Extension]) -> String {
+    let mut items = vec!["MESSAGES", "UIDNEXT", "UNSEEN"];
+    if enabled.contains(&Extension::CondStore) { items.push("HIGHESTMODSEQ") }
+    match utf8_names(enabled) {
+        true => status_response(name, mailbox, &items),
+        false => status_response(&encoding::encode_mutf7(name), mailbox, &items),
+    }
+}
+
+/// Formats an untagged STATUS, items [status_item] does not know are left out
+///
+fn status_response(name: &str, mailbox: &Mailbox, items: &[&str]) -> String {
+    let values: Vec<String> = items.iter()
+        .filter_map(|item| Some(format!("{} {}", item, status_item(mailbox, item)?)))
+        .collect();
+    format!("STATUS {} ({})", fetch::imap_string(name), values.join(" "))
+}
+
+/// The value of one STATUS item, RECENT is always 0 as arrivals are only recent to the session that has the mailbox
+/// selected. DELETED and SIZE come from IMAP4rev2
+///
+fn status_item(mailbox: &Mailbox, item: &str) -> Option<u64> {
+    Some(match item {
+        "MESSAGES" => mailbox.messages.len() as u64,
+        "RECENT" => 0,
+        "UIDNEXT" => mailbox.uid_next as u64,
+        "UIDVALIDITY" => mailbox.uid_validity as u64,
+        "UNSEEN" => mailbox.messages.iter().filter(|m| !m.flags.iter().any(|f| f.eq_ignore_ascii_case("\\Seen"))).count() as u64,
+        "HIGHESTMODSEQ" => mailbox.highest_modseq,
+        "DELETED" => mailbox.messages.iter().filter(|m| m.flags.iter().any(|f| f.eq_ignore_ascii_case("\\Deleted"))).count() as u64,
+        "SIZE" => mailbox.messages.iter().map(|m| m.size as u64).sum(),
+        _ => return None,
+    })
+}
+
+/// Whether mailbox names are sent and taken as UTF-8, which IMAP4rev2 requires and UTF8=ACCEPT asks for
+///
+fn utf8_names(enabled: &[Extension]) -> bool {
+    enabled.contains(&Extension::Utf8Accept) || enabled.contains(&Extension::Imap4Rev2)
+}
+
+/// Matches a mailbox name against a LIST pattern, `*` matches anything and `%` anything but the hierarchy delimiter.
+/// Names are compared case insensitively as the folders are
+///
+fn pattern_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let name: Vec<char> = name.to_lowercase().chars().collect();
+    wildcard_match(&pattern, &name)
+}
 
-        for (index, file) in dir.enumerate(){
-            let index = index + 1;
-            if res.len() >= seq_count { break };
-            if !seqs.contains(&index.to_string()) { continue };
-            
-            let uid = self.filename_to_uid(&file.as_ref().unwrap());
-            res.push(Email::new(&uid, &index.to_string(), file.as_ref().unwrap().path()).unwrap());
+/// [pattern_matches] on characters, one pattern character at a time over every prefix of the name so the work is
+/// bounded by the product of their lengths however many wildcards there are
+///
+fn wildcard_match(pattern: &[char], name: &[char]) -> bool {
+    // matched[i] is whether the pattern so far matches the first i characters of the name
+    let mut matched = vec![false; name.len() + 1];
+    matched[0] = true;
+    for &p in pattern {
+        let mut next = vec![false; name.len() + 1];
+        for i in 0..=name.len() {
+            next[i] = match p {
+                '*' => matched[i] || (i > 0 && next[i - 1]),
+                '%' => matched[i] || (i > 0 && next[i - 1] && name[i - 1] != '/'),
+                c => i > 0 && matched[i - 1] && name[i - 1] == c,
+            };
         }
-        Ok(res)
+        matched = next;
     }
-    /// Converts a DirEnty to its UID using the filename which is based on date
-    fn filename_to_uid(&self, file: &fs::DirEntry) -> String{
-        let mut filename = file.file_name().into_string().unwrap();
-        let shorter_filename = filename.split_off(3);
-        let uid_seed = shorter_filename.split("s.eml").next().unwrap().parse::<f64>().unwrap();
-        ((uid_seed * 10f64) as usize).to_string()
+    matched[name.len()]
+}
+
+/// The special use (RFC 6154) of a folder, going by the names mail clients give them
+///
+fn special_use(name: &str) -> Option<&'static str> {
+    match name.to_lowercase().as_str() {
+        "archive" => Some("\\Archive"),
+        "drafts" => Some("\\Drafts"),
+        "sent" | "sent items" | "sent messages" => Some("\\Sent"),
+        "junk" | "junk email" | "spam" => Some("\\Junk"),
+        "trash" | "deleted items" | "deleted messages" => Some("\\Trash"),
+        _ => None,
     }
 }
 
+/// Logs a session in as a user of its own whose Inbox holds the test emails, which the index numbers 1 to 4 in file
+/// name order, and selects it. The user's folders are removed when the returned guard is dropped
+///
+#[cfg(test)]
+fn test_session(name: &str) -> (UserSession, TestUser) {
+    let user = format!("imapserver-{}-{}", name, std::process::id());
+    let inbox = storage::user_root(&user).join("Inbox");
+    let _ = std::fs::remove_dir_all(storage::user_root(&user));
+    std::fs::create_dir_all(&inbox).unwrap();
+    for file in ["DisplayNames.eml", "Latin1.eml", "Multipart.eml", "NoDisplayNames.eml"] {
+        std::fs::copy(std::path::Path::new("test_emails").join(file), inbox.join(file)).unwrap();
+    }
+    let mut session = UserSession::new();
+    session.authenticate(&format!("\"{}@example.scot\" tset", user));
+    session.select("INBOX").unwrap();
+    (session, TestUser(user))
+}
+
+#[cfg(test)]
+struct TestUser(String);
+
+#[cfg(test)]
+impl Drop for TestUser{
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(storage::user_root(&self.0));
+    }
+}
+
+/// The FETCH responses of a test session as text, one per message
+///
+#[cfg(test)]
+fn fetch_text(session: &mut UserSession, msg: &str, uid: bool) -> Vec<String> {
+    let mut responses = Vec::new();
+    let write = |chunks: Vec<Chunk>| {
+        let text: Vec<u8> = chunks.into_iter().flat_map(|chunk| match chunk {
+            Chunk::Bytes(bytes) => bytes,
+            Chunk::File{ path, range } => std::fs::read(path).unwrap()[range].to_vec(),
+        }).collect();
+        responses.push(String::from_utf8_lossy(&text).into_owned());
+        Ok(())
+    };
+    match uid {
+        true => session.fetch_uid(msg, write).unwrap(),
+        false => session.fetch_seq(msg, write).unwrap(),
+    }
+    responses
+}
+
 #[test]
 fn fetch_seq_single(){
-    let mut session = UserSession::new();
-    session.authenticate("\"test@ashdown.scot\" tset");
-    
-    let res = session.fetch_seq("9 (UID)").unwrap();
-    println!("{:#?}", res);
+    let (mut session, _user) = test_session("fetch-seq-single");
+    assert_eq!(fetch_text(&mut session, "2 (UID)", false), vec!["2 FETCH (UID 2)\r\n"]);
 }
 #[test]
 fn fetch_seq_range(){
-    let mut session = UserSession::new();
-    session.authenticate("\"test@ashdown.scot\" tset");
-    
-    let res = session.fetch_seq("1:7 (UID)").unwrap();
-    println!("{:#?}", res);
+    let (mut session, _user) = test_session("fetch-seq-range");
+    assert_eq!(fetch_text(&mut session, "3:1 (UID)", false), vec!["1 FETCH (UID 1)\r\n", "2 FETCH (UID 2)\r\n", "3 FETCH (UID 3)\r\n"]);
+    assert_eq!(fetch_text(&mut session, "4:* (UID)", false), vec!["4 FETCH (UID 4)\r\n"]);
 }
 #[test]
 fn fetch_seq_list(){
-    let mut session = UserSession::new();
-    session.authenticate("\"test@ashdown.scot\" tset");
-    
-    let res = session.fetch_seq("1,2,4,5 (UID)").unwrap();
-    println!("{:#?}", res);
+    let (mut session, _user) = test_session("fetch-seq-list");
+    assert_eq!(fetch_text(&mut session, "1,2,4 (UID)", false), vec!["1 FETCH (UID 1)\r\n", "2 FETCH (UID 2)\r\n", "4 FETCH (UID 4)\r\n"]);
+    assert!(session.fetch_seq("5 (UID)", |_| Ok(())).is_err());
 }
 #[test]
 fn fetch_uid_single(){
-    let mut session = UserSession::new();
-    session.authenticate("\"test@ashdown.scot\" tset");
-    
-    let res = session.fetch_uid("87142369,87142369 (UID FLAGS RFC822.SIZE BODY.PEEK[] INTERNALDATE)").unwrap();
-    println!("{:#?}", res);
+    let (mut session, _user) = test_session("fetch-uid-single");
+    let message = std::fs::read("test_emails/Multipart.eml").unwrap();
+    let message = storage::normalise_crlf(&message);
+    let expected = format!("3 FETCH (UID 3 FLAGS () RFC822.SIZE {} BODY[] {{{}}}\r\n{})\r\n",
+        message.len(), message.len(), String::from_utf8_lossy(&message));
+    assert_eq!(fetch_text(&mut session, "3,3 (UID FLAGS RFC822.SIZE BODY.PEEK[])", true), vec![expected]);
+    // UIDs that do not exist are left out rather than refused
+    assert_eq!(fetch_text(&mut session, "4:9 (FLAGS)", true), vec!["4 FETCH (UID 4 FLAGS ())\r\n"]);
 }
 #[test]
 fn search(){
+    let (mut session, _user) = test_session("search");
+    assert_eq!(session.search("SINCE 04-Dec-2021", true).unwrap().matches, vec![1, 2, 3, 4]);
+    assert_eq!(session.search("SUBJECT \"Testing Email\"", true).unwrap().matches, vec![1, 4]);
+    assert_eq!(session.search("UID 2:3 NOT SUBJECT Multipart", false).unwrap().matches, vec![2]);
+}
+#[test]
+fn authenticate_sasl_plain(){
     let mut session = UserSession::new();
-    session.authenticate("\"test@ashdown.scot\" tset");
-    
-    let res = session.search("SINCE 04-Dec-2021").unwrap();
-    println!("{:#?}", res);
-}
\ No newline at end of file
+    assert!(!session.authenticate_plain(b"not base64!"));
+    assert!(!session.authenticated);
+    // authzid "other", authcid "test@x"
+    assert!(!session.authenticate_plain(b"b3RoZXIAdGVzdEB4AHB3"));
+    assert!(!session.authenticated);
+    assert!(session.authenticate_plain(b"AHRlc3RAeABwdw=="));
+    assert_eq!(session.username.as_deref(), Some("test"));
+}
+#[test]
+fn list_pattern_matching(){
+    assert!(pattern_matches("*", "Lists/rust"));
+    assert!(pattern_matches("inbox", "Inbox"));
+    assert!(pattern_matches("Lists/%", "Lists/rust"));
+    assert!(!pattern_matches("%", "Lists/rust"));
+    assert!(!pattern_matches("Arch", "Archive"));
+    assert!(pattern_matches("*/%e", "Lists/a/rustie"));
+    assert!(!pattern_matches("%/%", "Lists/a/rust"));
+    assert!(!pattern_matches(&"*a".repeat(30), &"a".repeat(29)));
+    assert_eq!(special_use("Sent Items"), Some("\\Sent"));
+    assert_eq!(special_use("Inbox"), None);
+}
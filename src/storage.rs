@@ -0,0 +1,628 @@
+//! Mailbox storage, each folder under the users mail directory holds `.eml` files plus an index
+//! that keeps the UIDs, flags, internal dates and mod-sequences IMAP needs to be stable between sessions
+//!
+use crate::error::{Result, Error};
+use crate::changes::{self, Change};
+use crate::encoding;
+use std::fs;
+use std::io::{Read, Write};
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use chrono::{DateTime, FixedOffset, TimeZone, Utc};
+
+pub static MAIL_ROOT: &str = "D:/MAILSERVER";
+static INDEX_FILE: &str = ".index";
+/// Expunged UIDs with the MODSEQ they were removed at, kept so QRESYNC clients can be sent VANISHED (EARLIER)
+///
+static EXPUNGE_LOG: &str = ".expunged";
+/// Days an expunge stays in [EXPUNGE_LOG] when config.aml has no `expunge_retention`
+///
+static DEFAULT_EXPUNGE_RETENTION: i64 = 30;
+/// Mailbox names a user subscribed to, one per line in their root. Until it exists every folder counts as subscribed
+///
+static SUBSCRIPTIONS_FILE: &str = ".subscriptions";
+/// The RFC 3501 `date-time` layout used by INTERNALDATE and APPEND, the day is space padded as `date-day-fixed` asks
+///
+static DATE_TIME_FORMAT: &str = "%e-%b-%Y %H:%M:%S %z";
+
+/// Held while an index is read, changed and written back so two sessions cannot hand out the same UID
+///
+static INDEX_LOCK: Mutex<()> = Mutex::new(());
+/// Bytes of a message file read at a time when sizing or normalising it
+///
+const NORMALISE_CHUNK: usize = 64 * 1024;
+
+/// An expunge log entry, the UID with the MODSEQ and time it was expunged at
+///
+type Expunged = (u32, u64, DateTime<FixedOffset>);
+
+/// One message as recorded in the mailbox index
+///
+#[derive(Debug, Clone)]
+pub struct MessageEntry{
+    pub uid: u32,
+    /// When the message arrived, set at delivery or from the APPEND date and kept with its original zone
+    pub internal_date: DateTime<FixedOffset>,
+    pub flags: Vec<String>,
+    pub file: String,
+    /// RFC822.SIZE, the length of the message once line endings are CRLF
+    pub size: usize,
+    /// MODSEQ (RFC 7162), the mailbox's HIGHESTMODSEQ when the message arrived or its flags last changed
+    pub modseq: u64,
+}
+
+/// How STORE changes flags, `FLAGS` replaces them, `+FLAGS` adds and `-FLAGS` removes
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StoreMode{
+    Replace,
+    Add,
+    Remove,
+}
+
+/// A folder on disk and its index, messages are kept in UID order so position + 1 is the sequence number
+///
+#[derive(Debug)]
+pub struct Mailbox{
+    path: PathBuf,
+    pub uid_validity: u32,
+    pub uid_next: u32,
+    /// HIGHESTMODSEQ (RFC 7162), raised by every change to the mailbox including expunges
+    pub highest_modseq: u64,
+    pub messages: Vec<MessageEntry>,
+}
+
+/// Formats an internal date as an IMAP `date-time` such as ` 4-Dec-2021 10:00:00 +0000`
+///
+pub fn format_date_time(date: &DateTime<FixedOffset>) -> String {
+    date.format(DATE_TIME_FORMAT).to_string()
+}
+
+/// Parses an IMAP `date-time` as given to APPEND, a leading space on single digit days is optional
+///
+pub fn parse_date_time(date: &str) -> Result<DateTime<FixedOffset>> {
+    DateTime::parse_from_str(date.trim_start(), "%d-%b-%Y %H:%M:%S %z").map_err(|_| Error::InvalidDate)
+}
+
+/// Reads the `date=` value of an index line, RFC 3339 with its zone or the UTC timestamps older indexes hold
+///
+fn parse_index_date(value: &str) -> DateTime<FixedOffset> {
+    DateTime::parse_from_rfc3339(value).unwrap_or_else(|_| {
+        // Anything unreadable or out of range becomes the epoch, which always exists
+        let seconds = value.parse().unwrap_or(0);
+        Utc.timestamp_opt(seconds, 0).single().unwrap_or_else(|| Utc.timestamp_opt(0, 0).unwrap()).into()
+    })
+}
+
+/// Escapes a file name for the `file=` field of the index as `%XX`, spaces and line breaks would otherwise end it
+///
+fn escape_file_name(file: &str) -> String {
+    file.chars().map(|c| match c {
+        ' ' | '%' | '=' => format!("%{:02X}", c as u8),
+        c if c.is_ascii_control() => format!("%{:02X}", c as u8),
+        c => c.to_string(),
+    }).collect()
+}
+
+/// Turns bare LF line endings into CRLF, files written by tools that use LF are served as RFC 5322 requires
+///
+pub fn normalise_crlf(data: &[u8]) -> Cow<'_, [u8]> {
+    let bare = data.iter().enumerate().filter(|&(i, &b)| b == b'\n' && (i == 0 || data[i - 1] != b'\r')).count();
+    if bare == 0 { return Cow::Borrowed(data) }
+    let mut out = Vec::with_capacity(data.len() + bare);
+    for (i, &b) in data.iter().enumerate() {
+        if b == b'\n' && (i == 0 || data[i - 1] != b'\r') { out.push(b'\r') }
+        out.push(b);
+    }
+    Cow::Owned(out)
+}
+
+/// Reads a message file [NORMALISE_CHUNK] bytes at a time, handing each chunk to `f` with the byte before it so a
+/// CRLF split between chunks is still seen
+///
+fn for_each_chunk(path: &Path, mut f: impl FnMut(&[u8], Option<u8>) -> Result<()>) -> Result<()> {
+    let mut file = fs::File::open(path).map_err(Error::IO)?;
+    let mut chunk = vec![0u8; NORMALISE_CHUNK];
+    let mut previous = None;
+    loop {
+        let read = file.read(&mut chunk).map_err(Error::IO)?;
+        if read == 0 { return Ok(()) }
+        f(&chunk[..read], previous)?;
+        previous = Some(chunk[read - 1]);
+    }
+}
+
+/// Whether the LF at `i` has no CR before it, `previous` being the byte before the chunk
+///
+fn is_bare_lf(chunk: &[u8], i: usize, previous: Option<u8>) -> bool {
+    chunk[i] == b'\n' && (if i == 0 { previous } else { Some(chunk[i - 1]) }) != Some(b'\r')
+}
+
+/// Size of a stored message as it will be served, [normalise_crlf] included
+///
+fn message_size(path: &Path) -> Result<usize> {
+    let mut size = 0;
+    for_each_chunk(path, |chunk, previous| {
+        size += chunk.len() + (0..chunk.len()).filter(|&i| is_bare_lf(chunk, i, previous)).count();
+        Ok(())
+    })?;
+    Ok(size)
+}
+
+/// Rewrites a message dropped into the folder with bare LF line endings as CRLF and returns its size, so the file
+/// matches what FETCH sends and large bodies can be copied from it by range. The new copy is written beside it and
+/// renamed over it, so the message is never left half written
+///
+fn normalise_file(path: &Path) -> Result<usize> {
+    let size = message_size(path)?;
+    if size == fs::metadata(path).map_err(Error::IO)?.len() as usize { return Ok(size) }
+
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let written = fs::File::create(&temp).map_err(Error::IO).and_then(|file| {
+        let mut out = std::io::BufWriter::new(file);
+        for_each_chunk(path, |chunk, previous| {
+            let mut start = 0;
+            for i in (0..chunk.len()).filter(|&i| is_bare_lf(chunk, i, previous)) {
+                out.write_all(&chunk[start..i]).and_then(|_| out.write_all(b"\r")).map_err(Error::IO)?;
+                start = i;
+            }
+            out.write_all(&chunk[start..]).map_err(Error::IO)
+        })?;
+        out.into_inner().map_err(|e| Error::IO(e.into_error()))?.sync_all().map_err(Error::IO)
+    }).and_then(|_| fs::rename(&temp, path).map_err(Error::IO));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp);
+        return Err(e)
+    }
+    Ok(size)
+}
+
+/// Returns the directory holding all of a users folders
+///
+pub fn user_root(user: &str) -> PathBuf {
+    Path::new(MAIL_ROOT).join("mail").join(user)
+}
+
+/// Finds the folder for a mailbox name, names are matched case insensitively as the folders on disk are `Inbox` etc.
+/// Clients without UTF8=ACCEPT send names in modified UTF-7 so those match the folder they decode to as well
+///
+pub fn mailbox_path(user: &str, name: &str) -> Option<PathBuf> {
+    let decoded = encoding::decode_mutf7(name);
+    let dir = fs::read_dir(user_root(user)).ok()?;
+    dir.filter_map(|f| f.ok())
+        .filter(|f| f.metadata().map(|m| m.is_dir()).unwrap_or(false))
+        .find(|f| {
+            let folder = f.file_name().to_string_lossy().to_string();
+            folder.eq_ignore_ascii_case(name) || decoded.as_deref().is_some_and(|d| folder.eq_ignore_ascii_case(d))
+        })
+        .map(|f| f.path())
+}
+
+/// The names a user subscribed to, [None] until they first SUBSCRIBE or UNSUBSCRIBE
+///
+pub fn subscriptions(user: &str) -> Option<Vec<String>> {
+    let data = fs::read_to_string(user_root(user).join(SUBSCRIPTIONS_FILE)).ok()?;
+    Some(data.lines().filter(|l| !l.is_empty()).map(|l| l.to_owned()).collect())
+}
+
+/// Replaces the names a user is subscribed to, they need not be folders that exist (RFC 3501 6.3.6)
+///
+pub fn save_subscriptions(user: &str, names: &[String]) -> Result<()> {
+    let data: String = names.iter().map(|n| format!("{}\n", n)).collect();
+    fs::write(user_root(user).join(SUBSCRIPTIONS_FILE), data).map_err(Error::IO)
+}
+
+impl Mailbox{
+    /// Opens one of a users mailboxes by its IMAP name
+    ///
+    pub fn open(user: &str, name: &str) -> Result<Self> {
+        let path = mailbox_path(user, name).ok_or(Error::MailboxNotFound)?;
+        Self::open_path(path)
+    }
+    /// Opens a mailbox folder directly, creating or updating its index to match the files present
+    ///
+    pub fn open_path(path: impl AsRef<Path>) -> Result<Self> {
+        let mut mailbox = Self{
+            path: path.as_ref().to_path_buf(),
+            uid_validity: 0,
+            uid_next: 1,
+            highest_modseq: 1,
+            messages: Vec::new(),
+        };
+        let _lock = INDEX_LOCK.lock().unwrap();
+        mailbox.reload()?;
+        Ok(mailbox)
+    }
+    /// The folder holding the mailbox, its change log is kept under this
+    ///
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    /// Path of the file holding a message
+    ///
+    pub fn message_path(&self, entry: &MessageEntry) -> PathBuf {
+        self.path.join(&entry.file)
+    }
+    /// Looks up a message by UID, messages are kept in UID order so this is a binary search
+    ///
+    pub fn by_uid(&self, uid: u32) -> Option<&MessageEntry> {
+        let index = self.messages.binary_search_by_key(&uid, |m| m.uid).ok()?;
+        self.messages.get(index)
+    }
+    /// Stores a new message in the mailbox and returns the UID it was given
+    ///
+    pub fn append(&mut self, data: &[u8], flags: Vec<String>, internal_date: DateTime<FixedOffset>) -> Result<u32> {
+        let _lock = INDEX_LOCK.lock().unwrap();
+        self.reload()?;
+
+        let uid = self.uid_next;
+        let file = format!("{}-{}.eml", self.uid_validity, uid);
+        let data = normalise_crlf(data);
+        fs::write(self.path.join(&file), &data).map_err(Error::IO)?;
+
+        let modseq = self.next_modseq();
+        self.messages.push(MessageEntry{ uid, internal_date, flags, file, size: data.len(), modseq });
+        self.uid_next += 1;
+        self.save()?;
+        changes::record(&self.path, [Change::Added(uid)]);
+        Ok(uid)
+    }
+    /// Copies messages into another mailbox keeping their flags and internal dates, returns the (source, destination) UID pairs
+    ///
+    pub fn copy_to(&self, uids: &[u32], dest: &mut Mailbox) -> Result<Vec<(u32, u32)>> {
+        let _lock = INDEX_LOCK.lock().unwrap();
+        self.copy_messages(uids, dest)
+    }
+    /// Copies messages into another mailbox and removes them from this one (MOVE), both under the one lock so no
+    /// other session sees them in both or neither. Returns the (source, destination) UID pairs
+    ///
+    pub fn move_to(&mut self, uids: &[u32], dest: &mut Mailbox) -> Result<Vec<(u32, u32)>> {
+        let _lock = INDEX_LOCK.lock().unwrap();
+        let pairs = self.copy_messages(uids, dest)?;
+        let moved: Vec<u32> = pairs.iter().map(|(uid, _)| *uid).collect();
+        self.remove_messages(&moved)?;
+        Ok(pairs)
+    }
+    /// [Mailbox::copy_to] with [INDEX_LOCK] held. Files are copied under temporary names and only renamed once all
+    /// are written, the index is saved once at the end and anything copied is deleted again if a step fails
+    ///
+    fn copy_messages(&self, uids: &[u32], dest: &mut Mailbox) -> Result<Vec<(u32, u32)>> {
+        dest.reload()?;
+        let (uid_next, highest_modseq, count) = (dest.uid_next, dest.highest_modseq, dest.messages.len());
+
+        let mut pairs = Vec::new();
+        let mut written: Vec<(PathBuf, PathBuf)> = Vec::new();
+        let result = (|| {
+            for entry in self.messages.iter().filter(|m| uids.contains(&m.uid)) {
+                let uid = dest.uid_next;
+                let file = format!("{}-{}.eml", dest.uid_validity, uid);
+                let temp = dest.path.join(format!("{}.tmp", file));
+                written.push((temp.clone(), dest.path.join(&file)));
+                fs::copy(self.message_path(entry), &temp).map_err(Error::IO)?;
+
+                let modseq = dest.next_modseq();
+                dest.messages.push(MessageEntry{ uid, internal_date: entry.internal_date, flags: entry.flags.clone(), file, size: entry.size, modseq });
+                dest.uid_next += 1;
+                pairs.push((entry.uid, uid));
+            }
+            for (temp, path) in written.iter() {
+                fs::rename(temp, path).map_err(Error::IO)?;
+            }
+            dest.save()
+        })();
+
+        if let Err(e) = result {
+            for (temp, path) in written {
+                let _ = fs::remove_file(temp);
+                let _ = fs::remove_file(path);
+            }
+            dest.messages.truncate(count);
+            dest.uid_next = uid_next;
+            dest.highest_modseq = highest_modseq;
+            return Err(e)
+        }
+        changes::record(&dest.path, pairs.iter().map(|(_, uid)| Change::Added(*uid)));
+        Ok(pairs)
+    }
+    /// Changes the flags of messages as STORE does, flags compare case insensitively and are never repeated. Messages
+    /// whose MODSEQ is above `unchanged_since` are left alone (RFC 7162 UNCHANGEDSINCE). Returns the UIDs whose flags
+    /// changed, each given a new MODSEQ, and the UIDs refused for `[MODIFIED]`
+    ///
+    pub fn store_flags(&mut self, uids: &[u32], flags: &[&str], mode: StoreMode, unchanged_since: Option<u64>) -> Result<(Vec<u32>, Vec<u32>)> {
+        let _lock = INDEX_LOCK.lock().unwrap();
+        self.reload()?;
+
+        let mut changed = Vec::new();
+        let mut modified = Vec::new();
+        for index in 0..self.messages.len() {
+            let entry = &mut self.messages[index];
+            if !uids.contains(&entry.uid) { continue }
+            if unchanged_since.is_some_and(|since| entry.modseq > since) {
+                modified.push(entry.uid);
+                continue
+            }
+            let before = entry.flags.clone();
+            match mode {
+                StoreMode::Replace => entry.flags.clear(),
+                StoreMode::Remove => entry.flags.retain(|f| !flags.iter().any(|r| r.eq_ignore_ascii_case(f))),
+                StoreMode::Add => {}
+            }
+            if mode != StoreMode::Remove {
+                for flag in flags {
+                    if !entry.flags.iter().any(|f| f.eq_ignore_ascii_case(flag)) { entry.flags.push(flag.to_string()) }
+                }
+            }
+            if entry.flags != before {
+                changed.push(entry.uid);
+                self.messages[index].modseq = self.next_modseq();
+            }
+        }
+        self.save()?;
+        changes::record(&self.path, changed.iter().map(|uid| Change::Flags(*uid)));
+        Ok((changed, modified))
+    }
+    /// Deletes messages from the mailbox, returns the sequence numbers they had in the order the
+    /// EXPUNGE responses must be sent (highest first, so earlier responses do not renumber later ones)
+    ///
+    pub fn remove(&mut self, uids: &[u32]) -> Result<Vec<usize>> {
+        let _lock = INDEX_LOCK.lock().unwrap();
+        self.remove_messages(uids)
+    }
+    /// [Mailbox::remove] with [INDEX_LOCK] held
+    ///
+    fn remove_messages(&mut self, uids: &[u32]) -> Result<Vec<usize>> {
+        self.reload()?;
+
+        let mut expunged = Vec::new();
+        for (index, entry) in self.messages.iter().enumerate().rev() {
+            if !uids.contains(&entry.uid) { continue }
+            fs::remove_file(self.message_path(entry)).map_err(Error::IO)?;
+            expunged.push(index + 1);
+        }
+        let removed: Vec<Change> = self.messages.iter().filter(|m| uids.contains(&m.uid)).map(|m| Change::Expunged(m.uid)).collect();
+        self.messages.retain(|m| !uids.contains(&m.uid));
+        self.log_expunged(&removed)?;
+        self.save()?;
+        changes::record(&self.path, removed);
+        Ok(expunged)
+    }
+    /// Reads the index and reconciles it with the folder, files that appeared get new UIDs and missing ones are dropped.
+    /// Must be called with [INDEX_LOCK] held
+    ///
+    fn reload(&mut self) -> Result<()> {
+        let mut changed = false;
+        match fs::read_to_string(self.path.join(INDEX_FILE)) {
+            Ok(index) => self.parse_index(&index),
+            Err(_) => {
+                self.uid_validity = Utc::now().timestamp() as u32;
+                self.uid_next = 1;
+                self.highest_modseq = 1;
+                self.messages.clear();
+                changed = true;
+            }
+        }
+
+        let mut files: Vec<(String, PathBuf)> = fs::read_dir(&self.path).map_err(Error::IO)?
+            .filter_map(|f| f.ok())
+            .map(|f| (f.file_name().to_string_lossy().to_string(), f.path()))
+            .filter(|(name, _)| name.ends_with(".eml"))
+            .collect();
+        files.sort();
+
+        // Files removed or dropped in by something other than this server are changes other sessions need to hear of
+        let mut found: Vec<Change> = self.messages.iter()
+            .filter(|m| !files.iter().any(|(name, _)| name == &m.file))
+            .map(|m| Change::Expunged(m.uid))
+            .collect();
+        self.messages.retain(|m| files.iter().any(|(name, _)| name == &m.file));
+        if !found.is_empty() {
+            self.log_expunged(&found)?;
+            changed = true;
+        }
+
+        for (name, path) in files {
+            if self.messages.iter().any(|m| m.file == name) { continue }
+            // Delivered straight into the folder, the time it was written is the closest to its arrival we have
+            let internal_date = fs::metadata(&path).and_then(|m| m.modified())
+                .map(DateTime::<Utc>::from)
+                .unwrap_or_else(|_| Utc::now())
+                .into();
+            let size = normalise_file(&path)?;
+            let modseq = self.next_modseq();
+            self.messages.push(MessageEntry{ uid: self.uid_next, internal_date, flags: Vec::new(), file: name, size, modseq });
+            found.push(Change::Added(self.uid_next));
+            self.uid_next += 1;
+            changed = true;
+        }
+        // Indexes written before sizes were kept get them filled in once, empty files stay at 0 without a rewrite
+        for entry in self.messages.iter_mut().filter(|m| m.size == 0) {
+            entry.size = message_size(&self.path.join(&entry.file))?;
+            changed |= entry.size != 0;
+        }
+        if changed { self.save()? }
+        changes::record(&self.path, found);
+        Ok(())
+    }
+    /// Raises HIGHESTMODSEQ for a change and returns the new value, the MODSEQ of whichever message changed
+    ///
+    fn next_modseq(&mut self) -> u64 {
+        self.highest_modseq += 1;
+        self.highest_modseq
+    }
+    /// Gives a batch of expunges a new MODSEQ and adds them to the expunge log, dropping entries older than the
+    /// retention. The log's first line holds the MODSEQ it is complete from, which moves up as entries are dropped.
+    /// Must be called with [INDEX_LOCK] held
+    ///
+    fn log_expunged(&mut self, removed: &[Change]) -> Result<()> {
+        if removed.is_empty() { return Ok(()) }
+        // Anything expunged before the log existed happened at or below the current HIGHESTMODSEQ
+        let (mut since, mut entries) = self.read_expunge_log().unwrap_or((self.highest_modseq, Vec::new()));
+        let modseq = self.next_modseq();
+        let now = Utc::now();
+        entries.extend(removed.iter().filter_map(|c| match c {
+            Change::Expunged(uid) => Some((*uid, modseq, now.into())),
+            _ => None,
+        }));
+
+        let config = aml::load("config.aml");
+        let days = config.get("expunge_retention").and_then(|d| d.parse().ok()).unwrap_or(DEFAULT_EXPUNGE_RETENTION);
+        let cutoff: DateTime<FixedOffset> = (now - chrono::Duration::days(days)).into();
+        for (_, modseq, _) in entries.iter().filter(|(_, _, date)| *date < cutoff) {
+            since = since.max(*modseq);
+        }
+        entries.retain(|(_, _, date)| *date >= cutoff);
+
+        let mut log = format!("since={}\n", since);
+        for (uid, modseq, date) in &entries {
+            log.push_str(&format!("uid={} modseq={} date={}\n", uid, modseq, date.to_rfc3339()));
+        }
+        fs::write(self.path.join(EXPUNGE_LOG), log).map_err(Error::IO)
+    }
+    /// Reads the expunge log as the MODSEQ it is complete from and its (UID, MODSEQ, date) entries
+    ///
+    fn read_expunge_log(&self) -> Option<(u64, Vec<Expunged>)> {
+        let log = fs::read_to_string(self.path.join(EXPUNGE_LOG)).ok()?;
+        let mut lines = log.lines();
+        let since = lines.next()?.strip_prefix("since=")?.parse().ok()?;
+        let entries = lines.filter_map(|line| {
+            let (mut uid, mut modseq, mut date) = (None, None, None);
+            for (key, value) in line.split_whitespace().filter_map(|kv| kv.split_once('=')) {
+                match key {
+                    "uid" => uid = value.parse().ok(),
+                    "modseq" => modseq = value.parse().ok(),
+                    "date" => date = DateTime::parse_from_rfc3339(value).ok(),
+                    _ => {}
+                }
+            }
+            Some((uid?, modseq?, date?))
+        });
+        Some((since, entries.collect()))
+    }
+    /// UIDs expunged after a mod-sequence in UID order, [None] when the expunge log no longer reaches back that far
+    ///
+    pub fn vanished_since(&self, modseq: u64) -> Option<Vec<u32>> {
+        let (since, entries) = self.read_expunge_log().unwrap_or((self.highest_modseq, Vec::new()));
+        if modseq < since { return None }
+        let mut uids: Vec<u32> = entries.iter().filter(|(_, m, _)| *m > modseq).map(|(uid, _, _)| *uid).collect();
+        uids.sort_unstable();
+        uids.dedup();
+        Some(uids)
+    }
+    /// Where the change log of this mailbox is up to, see [changes::position]
+    ///
+    pub fn change_position(&self) -> u64 {
+        changes::position(&self.path)
+    }
+    /// Changes to this mailbox since a position in its change log, see [changes::since]
+    ///
+    pub fn changes_since(&self, position: u64) -> Option<(Vec<Change>, u64)> {
+        changes::since(&self.path, position)
+    }
+    /// Parses the `key=value` index format, the first line describes the mailbox and the rest are one message per line
+    ///
+    fn parse_index(&mut self, index: &str) {
+        let mut lines = index.lines();
+        for (key, value) in lines.next().unwrap_or("").split_whitespace().filter_map(|kv| kv.split_once('=')) {
+            match key {
+                "uidvalidity" => self.uid_validity = value.parse().unwrap_or(1),
+                "uidnext" => self.uid_next = value.parse().unwrap_or(1),
+                "highestmodseq" => self.highest_modseq = value.parse().unwrap_or(1),
+                _ => {}
+            }
+        }
+        self.messages.clear();
+        for line in lines {
+            let mut entry = MessageEntry{ uid: 0, internal_date: Utc.timestamp_opt(0, 0).unwrap().into(), flags: Vec::new(), file: String::new(), size: 0, modseq: 1 };
+            for (key, value) in line.split_whitespace().filter_map(|kv| kv.split_once('=')) {
+                match key {
+                    "uid" => entry.uid = value.parse().unwrap_or(0),
+                    "date" => entry.internal_date = parse_index_date(value),
+                    "file" => entry.file = String::from_utf8_lossy(&encoding::percent_decode(value)).into_owned(),
+                    "size" => entry.size = value.parse().unwrap_or(0),
+                    "modseq" => entry.modseq = value.parse().unwrap_or(1),
+                    "flags" => entry.flags = value.split(',').filter(|f| !f.is_empty()).map(|f| f.to_owned()).collect(),
+                    _ => {}
+                }
+            }
+            if entry.uid != 0 && !entry.file.is_empty() {
+                self.messages.push(entry);
+            }
+        }
+        self.messages.sort_by_key(|m| m.uid);
+        // Indexes written before mod-sequences were kept start every message at 1
+        self.highest_modseq = self.messages.iter().map(|m| m.modseq).fold(self.highest_modseq.max(1), u64::max);
+    }
+    /// Writes the index back to disk
+    ///
+    fn save(&self) -> Result<()> {
+        let mut index = format!("uidvalidity={} uidnext={} highestmodseq={}\n", self.uid_validity, self.uid_next, self.highest_modseq);
+        for m in &self.messages {
+            index.push_str(&format!("uid={} date={} size={} modseq={} file={} flags={}\n", m.uid, m.internal_date.to_rfc3339(), m.size, m.modseq, escape_file_name(&m.file), m.flags.join(",")));
+        }
+        fs::write(self.path.join(INDEX_FILE), index).map_err(Error::IO)
+    }
+}
+
+#[test]
+fn index_assigns_and_keeps_uids(){
+    let dir = std::env::temp_dir().join(format!("imapserver-storage-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::copy("test_emails/DisplayNames.eml", dir.join("a.eml")).unwrap();
+
+    let mut mailbox = Mailbox::open_path(&dir).unwrap();
+    assert_eq!(mailbox.messages.len(), 1);
+    assert_eq!(mailbox.messages[0].uid, 1);
+
+    let date = parse_date_time(" 4-Dec-2021 10:00:00 +0100").unwrap();
+    let uid = mailbox.append(b"Subject: hi\r\n\r\nbody\r\n", vec!["\\Seen".into()], date).unwrap();
+    assert_eq!(uid, 2);
+
+    let reopened = Mailbox::open_path(&dir).unwrap();
+    assert_eq!(reopened.uid_validity, mailbox.uid_validity);
+    assert_eq!(reopened.by_uid(2).unwrap().flags, vec!["\\Seen".to_owned()]);
+    assert_eq!(reopened.by_uid(2).unwrap().size, 21);
+    assert_eq!(format_date_time(&reopened.by_uid(2).unwrap().internal_date), " 4-Dec-2021 10:00:00 +0100");
+    assert_eq!(format_date_time(&parse_index_date("1637686592")), "23-Nov-2021 16:56:32 +0000");
+    assert_eq!(normalise_crlf(b"a\nb\r\n\n").as_ref(), b"a\r\nb\r\n\r\n");
+    assert_eq!(escape_file_name("a b%=.eml"), "a%20b%25%3D.eml");
+
+    let mut copy = Mailbox::open_path(&dir).unwrap();
+    assert_eq!(mailbox.copy_to(&[2], &mut copy).unwrap(), vec![(2, 3)]);
+    assert_eq!(copy.by_uid(3).unwrap().flags, vec!["\\Seen".to_owned()]);
+    copy.store_flags(&[3], &["\\Flagged", "\\seen"], StoreMode::Add, None).unwrap();
+    let modseq = copy.by_uid(3).unwrap().modseq;
+    assert_eq!(copy.store_flags(&[3], &["\\SEEN"], StoreMode::Remove, Some(modseq - 1)).unwrap(), (vec![], vec![3]));
+    assert_eq!(copy.store_flags(&[3], &["\\SEEN"], StoreMode::Remove, Some(modseq)).unwrap(), (vec![3], vec![]));
+    assert_eq!(copy.by_uid(3).unwrap().flags, vec!["\\Flagged".to_owned()]);
+    assert_eq!(Mailbox::open_path(&dir).unwrap().highest_modseq, copy.by_uid(3).unwrap().modseq);
+    let before = copy.highest_modseq;
+    assert_eq!(copy.vanished_since(before - 1), None);
+    assert_eq!(copy.remove(&[1, 3]).unwrap(), vec![3, 1]);
+    assert_eq!(copy.messages.len(), 1);
+    assert_eq!(copy.vanished_since(before), Some(vec![1, 3]));
+    assert_eq!(copy.vanished_since(copy.highest_modseq), Some(vec![]));
+    fs::remove_dir_all(&dir).unwrap();
+}
+#[test]
+fn normalise_file_in_chunks(){
+    let dir = std::env::temp_dir().join(format!("imapserver-normalise-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    // A CRLF split across the chunk boundary must not gain a second CR
+    let mut data = vec![b'a'; NORMALISE_CHUNK - 1];
+    data.extend_from_slice(b"\r\nb\nc\n");
+    let path = dir.join("a.eml");
+    fs::write(&path, &data).unwrap();
+
+    assert_eq!(message_size(&path).unwrap(), data.len() + 2);
+    assert_eq!(normalise_file(&path).unwrap(), data.len() + 2);
+    assert_eq!(fs::read(&path).unwrap(), normalise_crlf(&data).as_ref());
+    assert!(!dir.join("a.eml.tmp").exists());
+    assert_eq!(normalise_file(&path).unwrap(), data.len() + 2);
+    assert!(normalise_file(&dir.join("gone.eml")).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -2,10 +2,31 @@
 //! 
 use std::net::TcpStream;
 use native_tls::{Identity, TlsAcceptor, TlsStream};
-use std::io::{BufReader, BufRead, Write, Read};
+use std::io::{Write, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::PathBuf;
 use crate::error::{Result, Error};
 use crate::types::Response;
-use aml;
+
+/// Size of the pieces message files are copied to the socket in, so a large body never sits in memory whole
+///
+const COPY_CHUNK: usize = 64 * 1024;
+
+/// Largest literal a client may send, bigger ones are refused with `[TOOBIG]` before any of it is read
+///
+const MAX_LITERAL: usize = 32 * 1024 * 1024;
+
+/// The certificate STARTTLS uses, TLS is only offered when it exists
+///
+static CERT_FILE: &str = "cert.pfx";
+
+/// Part of a response, either bytes built in memory or a byte range of a message file copied when written
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum Chunk{
+    Bytes(Vec<u8>),
+    File{ path: PathBuf, range: Range<usize> },
+}
 
 /// Struct for managing the reading and writing from TLS and TCP streams in a way that abstracts from the rest of the code
 ///
@@ -13,6 +34,11 @@ use aml;
 pub struct Stream{
     tcp_stream: TcpStream,
     tls_stream: Option<TlsStream<TcpStream>>,
+    buffer: Vec<u8>,
+    /// Whether tagged responses are held back until [Stream::release]
+    hold_tagged: bool,
+    /// The tagged response held back
+    held: Option<Vec<u8>>,
 }
 impl Stream{
     /// Creates a stream object from a TCP Stream
@@ -21,6 +47,9 @@ impl Stream{
         Self {
             tcp_stream,
             tls_stream: None,
+            buffer: Vec::new(),
+            hold_tagged: false,
+            held: None,
         }
     }
     /// Shuts down the TCP Stream
@@ -28,68 +57,219 @@ impl Stream{
     pub fn shutdown(&self) -> Result<()>{
         self.tcp_stream.shutdown(std::net::Shutdown::Both).map_err(Error::IO)
     }
-    /// Returns Peer Address
-    /// 
-    pub fn peer_addr(&self) -> std::net::SocketAddr {
-        self.tcp_stream.peer_addr().expect("Could not get peer IP Address")
+    /// Whether STARTTLS has completed on this connection
+    ///
+    pub fn is_secure(&self) -> bool {
+        self.tls_stream.is_some()
     }
-    /// This function reads a TCP stream until a CLRF `[13, 10]` is sent then collects into a [Vec]
-    pub fn read(&mut self) -> Result<String> {
-        
+    /// This function reads a TCP stream until a CLRF `[13, 10]` is sent then collects into a [Vec]. When the line ends
+    /// with a `{n}` literal the client is sent a continuation, then the literal and the rest of the command are read.
+    /// The non synchronising `{n+}` form (LITERAL+) skips the continuation. The command comes back as bytes since
+    /// literals may hold message data that is not UTF-8. A literal over [MAX_LITERAL] is refused with `NO [TOOBIG]`
+    /// and the next command read instead, unless it was `{n+}` and already on its way, which ends the connection
+    pub fn read(&mut self) -> Result<Vec<u8>> {
+
         let now = std::time::SystemTime::now();
-        let mut reader = BufReader::new(Read::by_ref(&mut self.tcp_stream));
-        let mut data: Vec<u8> = vec![];
+        let mut data = self.read_line(now)?;
+        while let Some((length, synchronising)) = literal_length(&data) {
+            if length > MAX_LITERAL {
+                let tag = String::from_utf8_lossy(data.split(|&b| b == b' ').next().unwrap_or(b"*")).to_string();
+                if !synchronising {
+                    self.write(Some(tag), Response::Bad, "[TOOBIG] Literal too large\r\n".into())?;
+                    return Err(Error::LiteralTooBig)
+                }
+                self.write(Some(tag), Response::No, "[TOOBIG] Literal too large\r\n".into())?;
+                data = self.read_line(now)?;
+                continue
+            }
+            if synchronising {
+                self.write(None, Response::Continuation, "Ready for literal data\r\n".into())?;
+            }
+            let literal = self.read_bytes(length, now)?;
+            data.extend_from_slice(&literal);
+            data.extend_from_slice(&self.read_line(now)?);
+        }
+        Ok(data)
+    }
+    /// Returns everything up to and including the next CRLF, bytes after it stay buffered for the next read
+    ///
+    fn read_line(&mut self, started: std::time::SystemTime) -> Result<Vec<u8>> {
         loop{
-            let buffer = reader.fill_buf();      
-            match buffer {
-                Ok(bytes) => {
-                    let length = bytes.len();
-                    data.extend_from_slice(bytes); 
-                    reader.consume(length);
-                    // Okay checks for CLFR if more than one byte is in buffer
-                    if (data.len() > 1) && (&data[data.len()-2..] == [13, 10]){
-                        break;
-                    }
+            if let Some(end) = self.buffer.windows(2).position(|w| w == [13, 10]) {
+                return Ok(self.buffer.drain(..end + 2).collect())
+            }
+            self.fill_buffer(started)?;
+        }
+    }
+    /// Waits up to `wait` for the client to send something, IDLE and NOTIFY push changes to the client between checks
+    ///
+    pub fn wait_for_data(&mut self, wait: std::time::Duration) -> Result<bool> {
+        if !self.buffer.is_empty() { return Ok(true) }
+
+        let previous = self.tcp_stream.read_timeout().map_err(Error::IO)?;
+        self.tcp_stream.set_read_timeout(Some(wait)).map_err(Error::IO)?;
+        let mut chunk = [0u8; 4096];
+        let read = self.read_socket(&mut chunk);
+        self.tcp_stream.set_read_timeout(previous).map_err(Error::IO)?;
+        match read {
+            Ok(0) => Err(Error::ConnectionClosed),
+            Ok(length) => {
+                self.buffer.extend_from_slice(&chunk[..length]);
+                Ok(true)
+            }
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => Ok(false),
+            Err(e) => Err(Error::IO(e)),
+        }
+    }
+    /// Returns exactly `length` bytes, used for literals which may contain CRLFs
+    ///
+    fn read_bytes(&mut self, length: usize, started: std::time::SystemTime) -> Result<Vec<u8>> {
+        while self.buffer.len() < length {
+            self.fill_buffer(started)?;
+        }
+        Ok(self.buffer.drain(..length).collect())
+    }
+    /// Reads whatever the socket has available into the buffer
+    ///
+    fn fill_buffer(&mut self, started: std::time::SystemTime) -> Result<()> {
+        let mut chunk = [0u8; 4096];
+        loop{
+            match self.read_socket(&mut chunk) {
+                Ok(0) => return Err(Error::ConnectionClosed),
+                Ok(length) => {
+                    self.buffer.extend_from_slice(&chunk[..length]);
+                    return Ok(())
                 },
                 _ => {}
             }
-            if now.elapsed().unwrap() > std::time::Duration::from_secs(119) {
+            if started.elapsed().unwrap() > std::time::Duration::from_secs(119) {
                 return Err(Error::TCPReadTimeout)
-            }      
+            }
         }
-        //println!("Data from client: {:?}", data);
-        let res = String::from_utf8_lossy(&data);
-        print!("C: {}", res);
-        Ok(res.to_string())
     }
     /// Wrapper around writing to TCP stream, handles the no whitespace requirement of the HELO response
     /// 
     pub fn write(&mut self, tag: Option<String>, response: Response, msg: String) -> Result<()> {
+        self.write_bytes(tag, response, msg.as_bytes())
+    }
+    /// Same as [Stream::write] for responses carrying message data, which is sent byte for byte
+    ///
+    pub fn write_bytes(&mut self, tag: Option<String>, response: Response, msg: &[u8]) -> Result<()> {
 
+        let tagged = tag.is_some();
         let tag = tag.unwrap_or("*".to_owned());
-        let res = match response{
-            Response::None => format!("{} {}", tag, msg),
-            _ => format!("{} {} {}", tag, response, msg),
+        let prefix = match response{
+            Response::None => format!("{} ", tag),
+            Response::Continuation => format!("{} ", response),
+            _ => format!("{} {} ", tag, response),
         };
-        print!("S: {}", res);
-        //print!("{:?}", res.as_bytes());
-        self.tcp_stream.write(res.as_bytes()).map_err(Error::IO)?;
+        let mut res = prefix.into_bytes();
+        res.extend_from_slice(msg);
+        if self.hold_tagged && tagged {
+            self.held = Some(res);
+            return Ok(())
+        }
+        self.write_socket(&res)?;
     
         Ok(())
     }
-    
-    /// Takes a TCP stream and inits a TLS stream if successful
+    /// Holds the next tagged response back until [Stream::release], so updates can be sent once a command has run
+    /// but before it completes
+    ///
+    pub fn hold_tagged(&mut self) {
+        self.hold_tagged = true;
+    }
+    /// Sends the tagged response held back, if there is one, and stops holding them
+    ///
+    pub fn release(&mut self) -> Result<()> {
+        self.hold_tagged = false;
+        match self.held.take() {
+            Some(res) => self.write_socket(&res),
+            None => Ok(()),
+        }
+    }
+    /// Writes an untagged response made of [Chunk]s in order, file ranges are read and sent [COPY_CHUNK] bytes at a time
+    ///
+    pub fn write_chunks(&mut self, chunks: &[Chunk]) -> Result<()> {
+        // Files are opened before anything is sent so one that has gone can still be answered, as [Error::CantReadEmail]
+        let mut files = chunks.iter()
+            .filter_map(|chunk| match chunk {
+                Chunk::File{ path, .. } => Some(std::fs::File::open(path).map_err(|_| Error::CantReadEmail)),
+                Chunk::Bytes(_) => None,
+            })
+            .collect::<Result<Vec<_>>>()?
+            .into_iter();
+        self.write_socket(b"* ")?;
+        for chunk in chunks {
+            match chunk {
+                Chunk::Bytes(data) => self.write_socket(data)?,
+                Chunk::File{ range, .. } => {
+                    let Some(mut file) = files.next() else { continue };
+                    file.seek(SeekFrom::Start(range.start as u64)).map_err(Error::IO)?;
+                    let mut remaining = range.len();
+                    let mut piece = vec![0u8; COPY_CHUNK.min(remaining)];
+                    while remaining > 0 {
+                        let length = remaining.min(COPY_CHUNK);
+                        file.read_exact(&mut piece[..length]).map_err(Error::IO)?;
+                        self.write_socket(&piece[..length])?;
+                        remaining -= length;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Reads from TLS once it has started, otherwise straight from TCP
+    ///
+    fn read_socket(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match &mut self.tls_stream {
+            Some(tls) => tls.read(buf),
+            None => self.tcp_stream.read(buf),
+        }
+    }
+    /// Writes to TLS once it has started, otherwise straight to TCP
+    ///
+    fn write_socket(&mut self, data: &[u8]) -> Result<()> {
+        match &mut self.tls_stream {
+            Some(tls) => tls.write_all(data),
+            None => self.tcp_stream.write_all(data),
+        }.map_err(Error::IO)
+    }
+
+    /// Takes a TCP stream and inits a TLS stream if successful. Anything the client sent after STARTTLS in the clear
+    /// is thrown away so it cannot be mistaken for commands sent over TLS
     /// 
     pub fn start_tls(&mut self) -> Result<()> {
-        let mut file = std::fs::File::open("cert.pfx").unwrap();
+        let mut file = std::fs::File::open(CERT_FILE).map_err(Error::IO)?;
         let config = aml::load("config.aml");
         let mut raw_cert = vec![];
-        file.read_to_end(&mut raw_cert).unwrap();
-        let identity = Identity::from_pkcs12(&raw_cert, config.get("cert_passphrase").unwrap()).unwrap();
+        file.read_to_end(&mut raw_cert).map_err(Error::IO)?;
+        let passphrase = config.get("cert_passphrase").ok_or(Error::TlsUnavailable)?;
+        let identity = Identity::from_pkcs12(&raw_cert, passphrase).map_err(|_| Error::TlsUnavailable)?;
         //let acceptor = TlsAcceptor::builder(identity).min_protocol_version(Some(native_tls::Protocol::Tlsv12)).build().unwrap();
-        let acceptor = TlsAcceptor::new(identity).unwrap();
-        let tls_stream = acceptor.accept(self.tcp_stream.try_clone().unwrap()).unwrap();
+        let acceptor = TlsAcceptor::new(identity).map_err(|_| Error::TlsUnavailable)?;
+        self.buffer.clear();
+        let tcp_stream = self.tcp_stream.try_clone().map_err(Error::IO)?;
+        let tls_stream = acceptor.accept(tcp_stream).map_err(|_| Error::TlsUnavailable)?;
         self.tls_stream = Some(tls_stream);
         Ok(())
     }
-}
\ No newline at end of file
+}
+/// Whether a certificate is installed so STARTTLS can be offered
+///
+pub fn tls_available() -> bool {
+    std::path::Path::new(CERT_FILE).exists()
+}
+/// Checks if a line ends with a literal marker, returning its length and whether the client waits for a continuation
+///
+fn literal_length(line: &[u8]) -> Option<(usize, bool)> {
+    let line = std::str::from_utf8(line.strip_suffix(b"}\r\n")?).ok()?;
+    let marker = &line[line.rfind('{')? + 1..];
+    // A length too long for usize is still a literal, just one that is too big
+    let length = |digits: &str| digits.parse().ok()
+        .or_else(|| (!digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())).then_some(usize::MAX));
+    match marker.strip_suffix('+') {
+        Some(digits) => Some((length(digits)?, false)),
+        None => Some((length(marker)?, true)),
+    }
+}
@@ -22,6 +22,43 @@ impl std::fmt::Display for Response{
     }
 }
 
+/// Extensions a client switches on for its connection with ENABLE (RFC 5161), responses change once they are
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Extension{
+    CondStore,
+    QResync,
+    Utf8Accept,
+    /// IMAP4rev2 (RFC 9051), the connection follows the newer protocol where it differs from IMAP4rev1
+    Imap4Rev2,
+}
+
+impl Extension{
+    /// Looks up an extension by the capability name ENABLE uses, [None] for those this server does not have
+    ///
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_uppercase().as_str() {
+            "CONDSTORE" => Some(Extension::CondStore),
+            "QRESYNC" => Some(Extension::QResync),
+            "UTF8=ACCEPT" => Some(Extension::Utf8Accept),
+            "IMAP4REV2" => Some(Extension::Imap4Rev2),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Extension{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        let s = match self{
+            Extension::CondStore => "CONDSTORE",
+            Extension::QResync => "QRESYNC",
+            Extension::Utf8Accept => "UTF8=ACCEPT",
+            Extension::Imap4Rev2 => "IMAP4rev2",
+        };
+        write!(f, "{}", s)
+    }
+}
+
 #[derive(Debug)]
 pub enum Command{
     Capability,
@@ -32,12 +69,28 @@ pub enum Command{
     List,
     Lsub,
     Select,
+    Examine,
     Status,
     Logout,
     Noop,
     Subscribe,
     Uid,
     Create,
+    Append,
+    Copy,
+    Move,
+    Search,
+    Store,
+    Expunge,
+    Idle,
+    Check,
+    Notify,
+    Enable,
+    StartTls,
+    Unselect,
+    Unsubscribe,
+    Close,
+    Namespace,
 }
 
 impl From<String> for Command{
@@ -51,50 +104,29 @@ impl From<String> for Command{
             "LIST" => Command::List,
             "LSUB" => Command::Lsub,
             "SELECT" => Command::Select,
+            "EXAMINE" => Command::Examine,
             "STATUS" => Command::Status,
             "SUBSCRIBE" => Command::Subscribe,
             "NOOP" => Command::Noop,
             "LOGOUT" => Command::Logout,
             "UID" => Command::Uid,
             "CREATE" => Command::Create,
+            "APPEND" => Command::Append,
+            "COPY" => Command::Copy,
+            "MOVE" => Command::Move,
+            "SEARCH" => Command::Search,
+            "STORE" => Command::Store,
+            "EXPUNGE" => Command::Expunge,
+            "IDLE" => Command::Idle,
+            "CHECK" => Command::Check,
+            "NOTIFY" => Command::Notify,
+            "ENABLE" => Command::Enable,
+            "STARTTLS" => Command::StartTls,
+            "UNSELECT" => Command::Unselect,
+            "UNSUBSCRIBE" => Command::Unsubscribe,
+            "CLOSE" => Command::Close,
+            "NAMESPACE" => Command::Namespace,
             _ => Command::Unrecognised,
         }   
     }
 }
-
-pub enum Month{
-    Jan = 1,
-    Feb = 2,
-    Mar = 3,
-    Apr = 4,
-    May = 5,
-    Jun = 6,
-    Jul = 7,
-    Aug = 8,
-    Sep = 9,
-    Oct = 10,
-    Nov = 11,
-    Dec = 12,
-}
-
-impl TryFrom<&str> for Month{
-    type Error = crate::Error;
-
-    fn try_from(s: &str) -> Result<Self, Self::Error>{
-        Ok(match s {
-            "Jan" => Month::Jan,
-            "Feb" => Month::Feb,
-            "Mar" => Month::Mar,
-            "Apr" => Month::Apr,
-            "May" => Month::May,
-            "Jun" => Month::Jun,
-            "Jul" => Month::Jul,
-            "Aug" => Month::Aug,
-            "Sep" => Month::Sep,
-            "Oct" => Month::Oct,
-            "Nov" => Month::Nov,
-            "Dec" => Month::Dec,
-            _ => return Err(crate::Error::NotAMonth)
-        })
-    }
-}
\ No newline at end of file